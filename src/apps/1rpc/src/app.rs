@@ -3,6 +3,8 @@ use std::prelude::v1::*;
 use apps::{Const, Getter, Var};
 use base::fs::read_file;
 use base::trace::Alive;
+use forwarder::TrustedProxies;
+use net_http::Uri;
 use std::time::Duration;
 
 use crate::{
@@ -91,6 +93,13 @@ impl Getter<OneRpcConfig> for App {
             ws_frame_size: 64 << 10,
             ws_keep_alive: Some(Duration::from_secs(10)),
             ws_max_body_length: Some(2 << 20),
+            trusted_proxies: TrustedProxies::new(&arg.trusted_proxies, &arg.client_ip_headers)
+                .unwrap(),
+            egress_probe: match arg.egress_probe.as_str() {
+                "" => None,
+                v => Some(Uri::new(v).unwrap()),
+            },
+            egress_refresh: Duration::from_secs(arg.egress_refresh_secs),
         }
     }
 }
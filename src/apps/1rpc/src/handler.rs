@@ -6,8 +6,9 @@ use std::time::Duration;
 
 use base::trace::Alive;
 use forwarder::{
-    sanitizer::SanitizedRequest, JsonrpcForwardContext, JsonrpcForwardRequest,
-    JsonrpcForwarderConfig, JsonrpcForwarderWsHandler, JsonrpcRequestMgr, JsonrpcResponseMgr,
+    sanitizer::{self, SanitizedRequest, WsIdMap},
+    JsonrpcForwardContext, JsonrpcForwardRequest, JsonrpcForwarderConfig,
+    JsonrpcForwarderWsHandler, JsonrpcRequestMgr, JsonrpcResponseMgr, JsonrpcRoute, WsLogSplitter,
 };
 use jsonrpc::Batchable;
 use net_http::{
@@ -102,6 +103,8 @@ pub struct RelayWsHandler {
     cfg: RelayWsHandlerConfig,
     router: OneRpcRouter,
     remote_ws_conns: BTreeMap<usize, (String, WsStreamClient)>, // conn_id(local) -> (rpc_path, ws_stream)
+    ws_ids: BTreeMap<usize, WsIdMap>,                           // conn_id(local) -> ids in flight
+    ws_logs: BTreeMap<usize, WsLogSplitter>,                    // conn_id(local) -> logs split
     ws_reqs: JsonrpcRequestMgr,
 }
 
@@ -111,6 +114,8 @@ impl RelayWsHandler {
             cfg,
             router,
             remote_ws_conns: BTreeMap::new(),
+            ws_ids: BTreeMap::new(),
+            ws_logs: BTreeMap::new(),
             ws_reqs: JsonrpcRequestMgr::new(),
         }
     }
@@ -131,7 +136,12 @@ impl JsonrpcForwarderWsHandler for RelayWsHandler {
                 return;
             }
         };
-        let remote_uri = match self.router.get_route(&rpc_path) {
+        let remote_uri = match self
+            .router
+            .get_route(&rpc_path)
+            .and_then(|v| v.ws_route())
+            .and_then(|v| v.upstreams.first().cloned())
+        {
             Some(v) => v,
             _ => {
                 ws_responses.add_single_error_msg_closed(ctx.conn_id, -32600, "Unknown path");
@@ -167,6 +177,8 @@ impl JsonrpcForwarderWsHandler for RelayWsHandler {
     fn on_connection_close(&mut self, conn_id: usize) {
         glog::debug!("ws_conn: -{}", conn_id);
         self.remote_ws_conns.remove(&conn_id);
+        self.ws_ids.remove(&conn_id);
+        self.ws_logs.remove(&conn_id);
         glog::debug!("remote_ws_conn: -{}", conn_id);
     }
 
@@ -211,13 +223,31 @@ impl JsonrpcForwarderWsHandler for RelayWsHandler {
             }
         };
 
-        let req = JsonrpcForwardRequest {
-            conn_id: ctx.conn_id,
-            rpc_path: rpc_path.clone(),
-            remote_uri: ws.cfg.endpoint.clone(),
-            sr: SanitizedRequest::new(req_body),
-            last_send: None,
-        };
+        let route = self.router.get_route(rpc_path);
+        let strip_from = route
+            .as_ref()
+            .map(|v| v.options.strip_from)
+            .unwrap_or_default();
+        let split_logs = route
+            .as_ref()
+            .map(|v| v.options.rules().contains(&"logs"))
+            .unwrap_or(true);
+        let sr =
+            sanitizer::protect_fingerprint(SanitizedRequest::from_raw(req_body, &data), strip_from);
+        let mut req = JsonrpcForwardRequest::new(
+            ctx.conn_id,
+            rpc_path.clone(),
+            JsonrpcRoute::single(ws.cfg.endpoint.clone()),
+            sr,
+        );
+        self.ws_ids.entry(ctx.conn_id).or_default().record(&req.sr);
+        if split_logs {
+            // after recording, the merged responses carry the recorded ids
+            self.ws_logs
+                .entry(ctx.conn_id)
+                .or_insert_with(WsLogSplitter::new)
+                .split(&mut req.sr.req_body);
+        }
         self.ws_reqs.push(req);
     }
 
@@ -264,18 +294,29 @@ impl JsonrpcForwarderWsHandler for RelayWsHandler {
         for (conn_id, (_, remote_conn)) in &mut self.remote_ws_conns {
             tick.to_busy();
             match remote_conn.read(&mut data) {
-                Ok(ty) => match ws_conns.get_mut(*conn_id) {
-                    Some(local_conn) => match local_conn.write_ty(ty, &data) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            glog::error!("ws_conn[{}] write error: {:?}", conn_id, e);
+                Ok(ty) => {
+                    if let Some(logs) = self.ws_logs.get_mut(conn_id) {
+                        data = match logs.restore(std::mem::take(&mut data)) {
+                            Some(v) => v,
+                            None => continue,
+                        };
+                    }
+                    if let Some(ids) = self.ws_ids.get_mut(conn_id) {
+                        data = ids.restore(std::mem::take(&mut data));
+                    }
+                    match ws_conns.get_mut(*conn_id) {
+                        Some(local_conn) => match local_conn.write_ty(ty, &data) {
+                            Ok(_) => {}
+                            Err(e) => {
+                                glog::error!("ws_conn[{}] write error: {:?}", conn_id, e);
+                            }
+                        },
+                        _ => {
+                            glog::error!("ws_conn: {} -> nil", conn_id);
+                            close_conn.push(*conn_id);
                         }
-                    },
-                    _ => {
-                        glog::error!("ws_conn: {} -> nil", conn_id);
-                        close_conn.push(*conn_id);
                     }
-                },
+                }
                 Err(WsError::WouldBlock) => continue,
                 Err(e) => {
                     glog::error!("remote_ws_conn[{}] read error: {:?}", conn_id, e);
@@ -285,6 +326,8 @@ impl JsonrpcForwarderWsHandler for RelayWsHandler {
         }
         for conn_id in close_conn {
             self.remote_ws_conns.remove(&conn_id);
+            self.ws_ids.remove(&conn_id);
+            self.ws_logs.remove(&conn_id);
             ws_conns.remove(conn_id);
         }
     }
@@ -6,9 +6,9 @@ use std::time::Duration;
 use base::trace::Alive;
 use forwarder::{
     JsonrpcForwarder, JsonrpcForwarderConfig, JsonrpcForwarderHandler, JsonrpcForwarderWsHandler,
-    JsonrpcResponseMgr,
+    JsonrpcResponseMgr, JsonrpcRoute, RouteConfig, TrustedProxies,
 };
-use net_http::{HttpWsServerContext, TickResult, WsDataType, WsServerConns};
+use net_http::{HttpWsServerContext, TickResult, Uri, WsDataType, WsServerConns};
 
 pub struct OneRpcConfig {
     pub listen_addr: String,
@@ -18,27 +18,30 @@ pub struct OneRpcConfig {
     pub ws_frame_size: usize,
     pub ws_keep_alive: Option<Duration>,
     pub ws_max_body_length: Option<usize>,
+    pub trusted_proxies: TrustedProxies,
+    pub egress_probe: Option<Uri>,
+    pub egress_refresh: Duration,
 }
 
 // static only
 #[derive(Clone)]
 pub struct OneRpcRouter {
-    endpoints: Arc<HashMap<String, net_http::Uri>>,
+    endpoints: Arc<HashMap<String, JsonrpcRoute>>,
 }
 
 impl OneRpcRouter {
     pub fn from_static(file_path: &str) -> Self {
-        let hmap = base::fs::parse_file::<HashMap<String, String>>(file_path).unwrap();
+        let hmap = base::fs::parse_file::<HashMap<String, RouteConfig>>(file_path).unwrap();
         glog::info!("static routes: {}", serde_json::to_string(&hmap).unwrap());
         let hmap = hmap
             .into_iter()
-            .map(|(k, v)| net_http::Uri::new(&v).map(|uri| (k, uri)))
+            .map(|(k, v)| JsonrpcRoute::from_config(v).map(|route| (k, route)))
             .collect::<Result<HashMap<_, _>, _>>();
         let endpoints = Arc::new(hmap.unwrap());
         Self { endpoints }
     }
 
-    pub fn get_route(&self, key: &str) -> Option<net_http::Uri> {
+    pub fn get_route(&self, key: &str) -> Option<JsonrpcRoute> {
         self.endpoints.get(key).cloned()
     }
 }
@@ -53,12 +56,13 @@ impl<W> JsonrpcForwarderHandler for OneRpcJsonrpcForwarderHandler<W>
 where
     W: JsonrpcForwarderWsHandler,
 {
-    fn get_http_uri(&self, key: &str) -> Option<net_http::Uri> {
-        self.router.get_route(key).filter(|v| v.scheme() == "https")
+    fn get_http_route(&self, key: &str) -> Option<JsonrpcRoute> {
+        self.router.get_route(key)?.http_route()
     }
 
     fn get_ws_uri(&self, key: &str) -> Option<net_http::Uri> {
-        self.router.get_route(key).filter(|v| v.scheme() == "wss")
+        let route = self.router.get_route(key)?.ws_route()?;
+        route.upstreams.first().cloned()
     }
 
     fn on_http_request(
@@ -117,6 +121,9 @@ impl<W: JsonrpcForwarderWsHandler> OneRpc<W> {
                 ws_frame_size: cfg.ws_frame_size,
                 ws_keep_alive: cfg.ws_keep_alive,
                 ws_max_body_length: cfg.ws_max_body_length,
+                trusted_proxies: cfg.trusted_proxies.clone(),
+                egress_probe: cfg.egress_probe.clone(),
+                egress_refresh: cfg.egress_refresh,
             },
             handler,
             alive.clone(),
@@ -10,6 +10,10 @@ pub struct Args {
     pub routes: String,
     pub tls: String,
     pub submitter: String,
+    pub trusted_proxies: Vec<String>,   // cidrs
+    pub client_ip_headers: Vec<String>, // in order
+    pub egress_probe: String,           // empty to disable
+    pub egress_refresh_secs: u64,
 }
 
 impl Default for Args {
@@ -21,6 +25,13 @@ impl Default for Args {
             routes: "config.json".into(),
             tls: "".into(),
             submitter: "0x0000000000000000000000000000000000000000000000000000000000000000".into(),
+            trusted_proxies: vec![],
+            client_ip_headers: forwarder::DEFAULT_CLIENT_IP_HEADERS
+                .iter()
+                .map(|v| v.to_string())
+                .collect(),
+            egress_probe: "".into(), // opt-in, not to tell a third party about us
+            egress_refresh_secs: 600,
         }
     }
 }
@@ -47,6 +58,18 @@ impl Args {
                 Opt::Short('s') | Opt::Long("submitter") => {
                     out.submitter = opts.value().unwrap().parse().unwrap();
                 }
+                Opt::Short('p') | Opt::Long("trusted-proxies") => {
+                    out.trusted_proxies = split_list(opts.value().unwrap());
+                }
+                Opt::Long("client-ip-headers") => {
+                    out.client_ip_headers = split_list(opts.value().unwrap());
+                }
+                Opt::Long("egress-probe") => {
+                    out.egress_probe = opts.value().unwrap().parse().unwrap();
+                }
+                Opt::Long("egress-refresh-secs") => {
+                    out.egress_refresh_secs = opts.value().unwrap().parse().unwrap();
+                }
                 _ => continue,
             }
         }
@@ -57,3 +80,12 @@ impl Args {
         self.submitter == "0x0000000000000000000000000000000000000000000000000000000000000000".to_string()
     }
 }
+
+// "a,b, c" -> ["a", "b", "c"]
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .collect()
+}
@@ -0,0 +1,407 @@
+use std::prelude::v1::*;
+
+use base::time;
+use eth_types::{H160, SU256};
+use hex::HexBytes;
+use jsonrpc::{JsonrpcErrorObj, JsonrpcRawRequest, JsonrpcResponseRawResult};
+use serde::{Deserialize, Serialize};
+
+use crate::block::BlockParam;
+use crate::sanitizer::{sub_request_error, AccountRelationship, SubRequest, Transform};
+use crate::sol::{self, SolType, SolValue};
+use crate::utils;
+
+// a balance-scanner like call described in the routes config, e.g. the
+// balance checker contract:
+//   {
+//     "name": "balance_checker",
+//     "selector": "0xf0002ea9",
+//     "inputs": ["address[]", "address[]"],
+//     "accounts": 0,
+//     "items": 1,
+//     "call": { "method": "eth_call", "to": "item", "selector": "0x70a08231", "args": ["account"] },
+//     "zero_item_balance": true,
+//     "output": "uint256[]"
+//   }
+// is split into one `balanceOf(account)` per (account, token), account-major
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AbiRuleConfig {
+    pub name: String,
+    // 4 bytes of the function, hex
+    pub selector: String,
+    // canonical types of the arguments
+    pub inputs: Vec<String>,
+    // contracts the rule applies to, at least one. calls of the selector to
+    // any other contract are forwarded as is
+    pub to: Vec<String>,
+    // index of the account array in `inputs`
+    pub accounts: usize,
+    // index of an address array crossed with the accounts
+    #[serde(default)]
+    pub items: Option<usize>,
+    // sent per account, or per (account, item)
+    pub call: SubCallConfig,
+    // a zero address item queries the native balance of the account instead
+    #[serde(default)]
+    pub zero_item_balance: bool,
+    pub output: AbiOutput,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "method")]
+pub enum SubCallConfig {
+    #[serde(rename = "eth_getBalance")]
+    GetBalance,
+    #[serde(rename = "eth_call")]
+    Call {
+        to: String, // "target", "item" or an address
+        selector: String,
+        args: Vec<String>, // "account" or "item", each encoded as address
+    },
+}
+
+// how the results of the sub-calls are encoded as the result of the call
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum AbiOutput {
+    // each result read as one uint256, empty data as 0
+    #[serde(rename = "uint256[]")]
+    Uint256Array,
+    // return data of each sub-call as is
+    #[serde(rename = "bytes[]")]
+    BytesArray,
+}
+
+#[derive(Clone, Debug)]
+enum CallTarget {
+    Target, // the contract the client called
+    Item,
+    Address(H160),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum CallArg {
+    Account,
+    Item,
+}
+
+#[derive(Clone, Debug)]
+enum SubCall {
+    GetBalance,
+    Call {
+        to: CallTarget,
+        selector: [u8; 4],
+        args: Vec<CallArg>,
+    },
+}
+
+// `AbiRuleConfig` checked and parsed once when the config is loaded
+#[derive(Clone, Debug)]
+pub struct AbiRule {
+    cfg: AbiRuleConfig,
+    selector: [u8; 4],
+    inputs: Vec<SolType>,
+    to: Vec<H160>,
+    call: SubCall,
+}
+
+impl AbiRule {
+    pub fn new(cfg: AbiRuleConfig) -> Result<Self, String> {
+        let name = cfg.name.clone();
+        let fail = |msg: String| format!("abi rule {}: {}", name, msg);
+
+        let selector = parse_selector(&cfg.selector).map_err(&fail)?;
+        let inputs = cfg
+            .inputs
+            .iter()
+            .map(|v| SolType::parse(v).map_err(|e| fail(format!("{:?}", e))))
+            .collect::<Result<Vec<_>, _>>()?;
+        let address_array = SolType::Array(Box::new(SolType::Address));
+        for idx in Some(cfg.accounts).iter().chain(cfg.items.iter()) {
+            if inputs.get(*idx) != Some(&address_array) {
+                return Err(fail(format!("input {} is not address[]", idx)));
+            }
+        }
+        let to = cfg
+            .to
+            .iter()
+            .map(|v| utils::parse_address(v).ok_or_else(|| fail(format!("invalid to {}", v))))
+            .collect::<Result<Vec<_>, _>>()?;
+        // the selector alone may belong to any contract
+        if to.is_empty() {
+            return Err(fail("empty to".into()));
+        }
+
+        let has_items = cfg.items.is_some();
+        let need_items = |what: &str| -> Result<(), String> {
+            if has_items {
+                Ok(())
+            } else {
+                Err(fail(format!("{} without items", what)))
+            }
+        };
+        if cfg.zero_item_balance {
+            need_items("zero_item_balance")?;
+        }
+        let call = match &cfg.call {
+            SubCallConfig::GetBalance => SubCall::GetBalance,
+            SubCallConfig::Call { to, selector, args } => {
+                let to = match to.as_str() {
+                    "target" => CallTarget::Target,
+                    "item" => {
+                        need_items("call to item")?;
+                        CallTarget::Item
+                    }
+                    v => match utils::parse_address(v) {
+                        Some(v) => CallTarget::Address(v),
+                        None => return Err(fail(format!("invalid call to {}", v))),
+                    },
+                };
+                let selector = parse_selector(selector).map_err(&fail)?;
+                let mut call_args = vec![];
+                for arg in args {
+                    call_args.push(match arg.as_str() {
+                        "account" => CallArg::Account,
+                        "item" => {
+                            need_items("item arg")?;
+                            CallArg::Item
+                        }
+                        v => return Err(fail(format!("unknown arg {}", v))),
+                    });
+                }
+                SubCall::Call {
+                    to,
+                    selector,
+                    args: call_args,
+                }
+            }
+        };
+
+        Ok(Self {
+            selector,
+            inputs,
+            to,
+            call,
+            cfg,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.cfg.name
+    }
+
+    pub fn output(&self) -> AbiOutput {
+        self.cfg.output
+    }
+
+    pub fn selector(&self) -> [u8; 4] {
+        self.selector
+    }
+
+    // contracts the rule applies to
+    pub fn to(&self) -> &[H160] {
+        &self.to
+    }
+
+    // sub-requests and the block they are sent with, `None` if the rule
+    // doesn't apply to `req`
+    pub fn decompose(
+        &self,
+        req: &JsonrpcRawRequest,
+    ) -> Option<(Vec<SubRequest>, BlockParam, Transform)> {
+        macro_rules! decode_fail {
+            ($msg:expr) => {{
+                glog::warn!("abi rule {} abort: {}", self.cfg.name, $msg);
+                return None;
+            }};
+        }
+
+        if req.method != "eth_call" {
+            return None;
+        }
+
+        let calldata = utils::get_eth_call_data_from_jsonrpc(req)?;
+        let data = calldata.as_bytes();
+        if !sol::func_sig_matches(data, &self.selector) {
+            return None;
+        }
+        let target = match utils::get_eth_call_to_from_jsonrpc(req) {
+            Some(v) => v,
+            None => decode_fail!("to"),
+        };
+        if !self.to.contains(&target) {
+            return None;
+        }
+
+        let args = match sol::decode(&self.inputs, &data[4..]) {
+            Ok(v) => v,
+            Err(e) => decode_fail!(format!("{:?}", e)),
+        };
+        let addresses = |idx: usize| -> Vec<H160> {
+            args[idx]
+                .as_list()
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|v| v.as_address().cloned())
+                .collect()
+        };
+        let accounts = addresses(self.cfg.accounts);
+        let items = self.cfg.items.map(addresses);
+        let block = match BlockParam::parse(utils::get_jsonrpc_param(req, 1).as_ref()) {
+            Some(v) => v,
+            None => decode_fail!("block"),
+        };
+
+        let now = time::Date::from(time::now()).to_string();
+        let mut subs = vec![];
+        let mut protected = vec![];
+        for account in &accounts {
+            let per_account = match &items {
+                Some(items) => items.iter().map(Some).collect(),
+                None => vec![None],
+            };
+            for item in per_account {
+                let (sub, rel) = self.sub_call(&target, account, item, &now);
+                subs.push(sub);
+                protected.push(rel);
+            }
+        }
+        if subs.is_empty() {
+            decode_fail!("empty accounts or items");
+        }
+
+        let tr = Transform::AccountRelationship {
+            protected,
+            unprotected: AccountRelationship {
+                accounts: accounts.iter().map(|v| format!("{:?}", v)).collect(),
+                method: "eth_call",
+                params: vec![self.cfg.selector.to_ascii_lowercase(), block.describe()],
+                time: now,
+            },
+        };
+        Some((subs, block, tr))
+    }
+
+    fn sub_call(
+        &self,
+        target: &H160,
+        account: &H160,
+        item: Option<&H160>,
+        now: &str,
+    ) -> (SubRequest, AccountRelationship) {
+        let acct = format!("{:?}", account);
+        let native = self.cfg.zero_item_balance && item.map(|v| v.is_zero()).unwrap_or(false);
+        let (sub, method, params) = match &self.call {
+            SubCall::Call { to, selector, args } if !native => {
+                let to = match to {
+                    CallTarget::Target => target,
+                    CallTarget::Item => item.unwrap_or(target),
+                    CallTarget::Address(v) => v,
+                };
+                let to = format!("{:?}", to);
+                let args = args
+                    .iter()
+                    .map(|v| match v {
+                        CallArg::Account => SolValue::Address(account.clone()),
+                        CallArg::Item => SolValue::Address(item.cloned().unwrap_or_default()),
+                    })
+                    .collect::<Vec<_>>();
+                let mut data = selector.to_vec();
+                data.extend(sol::encode(&args));
+                let txn = serde_json::json!({
+                    "to": to,
+                    "data": String::from("0x") + &hex::encode(data),
+                });
+                let sub = SubRequest {
+                    method: "eth_call".into(),
+                    params: vec![txn],
+                    decoy: false,
+                    upstream: None,
+                };
+                let sig = String::from("0x") + &hex::encode(&selector[..]);
+                (sub, "eth_call", vec![sig, to])
+            }
+            _ => {
+                let sub = SubRequest {
+                    method: "eth_getBalance".into(),
+                    params: vec![serde_json::json!(acct)],
+                    decoy: false,
+                    upstream: None,
+                };
+                (sub, "eth_getBalance", vec![])
+            }
+        };
+        let rel = AccountRelationship {
+            accounts: vec![acct],
+            method,
+            params,
+            time: now.to_owned(),
+        };
+        (sub, rel)
+    }
+}
+
+// put results of the sub-calls together as `output`
+pub fn rewrite_response(
+    output: AbiOutput,
+    subs: &[SubRequest],
+    rs: Vec<Option<JsonrpcResponseRawResult>>,
+) -> Result<Vec<u8>, JsonrpcErrorObj> {
+    let err = sub_request_error;
+
+    let mut values = vec![];
+    for (req, r) in subs.iter().zip(rs) {
+        if req.decoy {
+            continue;
+        }
+        let v = match r {
+            Some(JsonrpcResponseRawResult::Ok(v)) => v,
+            Some(JsonrpcResponseRawResult::Err(e)) => {
+                glog::error!(
+                    "abi_rule_error: remote_response contains error {:?}",
+                    e.error
+                );
+                return Err(err());
+            }
+            None => {
+                glog::error!("abi_rule_error: remote_response missing");
+                return Err(err());
+            }
+        };
+        let value = match req.method.as_str() {
+            "eth_getBalance" => serde_json::from_raw_value::<SU256>(&v.result)
+                .ok()
+                .map(|v| match output {
+                    AbiOutput::Uint256Array => SolValue::Uint(*v),
+                    AbiOutput::BytesArray => SolValue::Bytes(sol::encode_uint256(&*v).to_vec()),
+                }),
+            _ => serde_json::from_raw_value::<HexBytes>(&v.result)
+                .ok()
+                .map(|v| match output {
+                    AbiOutput::Uint256Array => {
+                        SolValue::Uint(sol::decode_uint256(v.as_bytes()).unwrap_or_default())
+                    }
+                    AbiOutput::BytesArray => SolValue::Bytes(v.as_bytes().to_vec()),
+                }),
+        };
+        match value {
+            Some(v) => values.push(v),
+            None => {
+                glog::error!("abi_rule_error: deser jsonrpc");
+                return Err(err());
+            }
+        }
+    }
+    Ok(sol::encode(&[SolValue::Array(values)]))
+}
+
+fn parse_selector(s: &str) -> Result<[u8; 4], String> {
+    let data = s.strip_prefix("0x").and_then(|v| hex::decode(v).ok());
+    match data {
+        Some(v) if v.len() == 4 => {
+            let mut selector = [0; 4];
+            selector.copy_from_slice(&v);
+            Ok(selector)
+        }
+        _ => Err(format!("invalid selector {}", s)),
+    }
+}
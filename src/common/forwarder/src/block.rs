@@ -0,0 +1,93 @@
+use std::prelude::v1::*;
+
+use eth_types::{SU256, U256};
+use serde::Deserialize;
+use serde_json::Value;
+
+// block parameter of an `eth_call`-like request
+// https://eips.ethereum.org/EIPS/eip-1898
+#[derive(Clone, Debug)]
+pub enum BlockParam {
+    Tag(String), // latest, pending, safe, finalized
+    Number(U256),
+    Hash(Value), // `{"blockHash": ..}`, kept as the client sent it
+}
+
+#[derive(Deserialize)]
+struct BlockHeader {
+    number: SU256,
+}
+
+impl BlockParam {
+    // a missing parameter means "latest"
+    pub fn parse(v: Option<&Value>) -> Option<Self> {
+        let v = match v {
+            Some(v) => v,
+            None => return Some(BlockParam::Tag("latest".into())),
+        };
+        match v {
+            Value::String(tag) => match tag.as_str() {
+                "latest" | "pending" | "safe" | "finalized" => Some(BlockParam::Tag(tag.clone())),
+                "earliest" => Some(BlockParam::Number(U256::zero())),
+                _ => serde_json::from_value::<SU256>(v.clone())
+                    .ok()
+                    .map(|n| BlockParam::Number(*n)),
+            },
+            Value::Object(obj) => {
+                if let Some(n) = obj.get("blockNumber") {
+                    return BlockParam::parse(Some(n));
+                }
+                obj.get("blockHash")?.as_str()?;
+                Some(BlockParam::Hash(v.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    // (method, params) to find out the concrete block number
+    pub fn resolve_request(&self) -> Option<(&'static str, Value)> {
+        match self {
+            BlockParam::Tag(tag) => Some(("eth_getBlockByNumber", serde_json::json!([tag, false]))),
+            BlockParam::Hash(obj) => Some((
+                "eth_getBlockByHash",
+                serde_json::json!([obj.get("blockHash"), false]),
+            )),
+            BlockParam::Number(_) => None,
+        }
+    }
+
+    // parameter to send upstream once `number` is known. pending state can't
+    // be addressed by number, it's sent unpinned, `number` is only reported
+    pub fn pinned_param(&self, number: &U256) -> Value {
+        match self {
+            BlockParam::Hash(obj) => obj.clone(),
+            BlockParam::Tag(tag) if tag == "pending" => Value::String(tag.clone()),
+            _ => Value::String(format!("0x{:x}", number)),
+        }
+    }
+
+    pub fn number(&self) -> Option<U256> {
+        match self {
+            BlockParam::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            BlockParam::Tag(tag) => tag.clone(),
+            BlockParam::Number(n) => format!("0x{:x}", n),
+            BlockParam::Hash(obj) => obj
+                .get("blockHash")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_owned(),
+        }
+    }
+}
+
+pub fn parse_block_number(result: &serde_json::value::RawValue) -> Option<U256> {
+    serde_json::from_raw_value::<BlockHeader>(result)
+        .ok()
+        .map(|v| *v.number)
+}
@@ -4,14 +4,42 @@ use net_http::{HttpConnClientPool, Uri};
 use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 use std::ops::{Deref, DerefMut};
+use std::time::Duration;
 
+use crate::rng::Rng;
+use crate::route::{BalanceStrategy, JsonrpcRoute};
 use crate::ForwarderError;
 
-pub struct HttpForwardClient(BTreeMap<String, HttpConnClientPool>);
+// weight of the latest latency in the moving average
+const EWMA_ALPHA: f64 = 0.3;
+
+// pool of the route's `idx`th upstream
+pub fn pool_key(rpc_path: &str, idx: usize) -> String {
+    format!("{}#{}", rpc_path, idx)
+}
+
+#[derive(Default)]
+struct UpstreamLoad {
+    in_flight: usize,
+    ewma_ms: Option<f64>, // `None` until the first answer
+}
+
+pub struct HttpForwardClient {
+    pools: BTreeMap<String, HttpConnClientPool>,
+    loads: BTreeMap<String, UpstreamLoad>, // by pool key
+    turns: BTreeMap<String, usize>,        // rpc_path -> next round robin upstream
+    rng: Rng,
+}
 
 impl HttpForwardClient {
-    pub fn new() -> Self {
-        Self(BTreeMap::new())
+    // `rng` breaks ties and draws weighted picks, seed it for repeatable picks
+    pub fn new(rng: Rng) -> Self {
+        Self {
+            pools: BTreeMap::new(),
+            loads: BTreeMap::new(),
+            turns: BTreeMap::new(),
+            rng,
+        }
     }
 
     pub fn get_or_new(
@@ -19,7 +47,7 @@ impl HttpForwardClient {
         key: String,
         uri: &Uri,
     ) -> Result<&mut HttpConnClientPool, ForwarderError> {
-        let conn = match self.0.entry(key) {
+        let conn = match self.pools.entry(key) {
             Entry::Occupied(entry) => entry.into_mut(),
             Entry::Vacant(entry) => {
                 let conn = HttpConnClientPool::new(2, uri)?;
@@ -28,18 +56,230 @@ impl HttpForwardClient {
         };
         Ok(conn)
     }
+
+    // upstream of the route a client request starts from, among the ones
+    // `available` says are in rotation. if none is, among all of them
+    pub fn pick<F>(&mut self, rpc_path: &str, route: &JsonrpcRoute, available: F) -> usize
+    where
+        F: Fn(usize) -> bool,
+    {
+        let n = route.upstreams.len();
+        if n <= 1 {
+            return 0;
+        }
+        let mut candidates = (0..n).filter(|idx| available(*idx)).collect::<Vec<_>>();
+        if candidates.is_empty() {
+            candidates = (0..n).collect();
+        }
+        let policy = &route.options.balance;
+        match policy.strategy {
+            BalanceStrategy::RoundRobin => {
+                let turn = self.turns.entry(rpc_path.to_owned()).or_default();
+                let idx = (0..n)
+                    .map(|i| (*turn + i) % n)
+                    .find(|idx| candidates.contains(idx))
+                    .unwrap_or(0);
+                *turn = idx + 1;
+                idx
+            }
+            BalanceStrategy::Weighted => {
+                let total = candidates
+                    .iter()
+                    .map(|idx| policy.weight(*idx))
+                    .sum::<u64>();
+                if total == 0 {
+                    // only zero weight ones left, they stand in
+                    return candidates[self.rng.below(candidates.len() as u64) as usize];
+                }
+                let mut point = self.rng.below(total);
+                for idx in candidates {
+                    let weight = policy.weight(idx);
+                    if point < weight {
+                        return idx;
+                    }
+                    point -= weight;
+                }
+                0
+            }
+            BalanceStrategy::LeastOutstanding => self.pick_lowest(rpc_path, &candidates, |load| {
+                load.map(|v| v.in_flight as f64).unwrap_or(0.0)
+            }),
+            // never answered ones go first, to learn their latency
+            BalanceStrategy::Ewma => self.pick_lowest(rpc_path, &candidates, |load| match load {
+                Some(v) => v.ewma_ms.unwrap_or(0.0) * (v.in_flight + 1) as f64,
+                None => 0.0,
+            }),
+        }
+    }
+
+    // the candidate of the lowest score, ties picked at random
+    fn pick_lowest<F>(&mut self, rpc_path: &str, candidates: &[usize], score: F) -> usize
+    where
+        F: Fn(Option<&UpstreamLoad>) -> f64,
+    {
+        let scores = candidates
+            .iter()
+            .map(|idx| score(self.loads.get(&pool_key(rpc_path, *idx))))
+            .collect::<Vec<_>>();
+        let lowest = scores.iter().cloned().fold(f64::INFINITY, f64::min);
+        let ties = candidates
+            .iter()
+            .zip(&scores)
+            .filter(|(_, score)| **score <= lowest)
+            .map(|(idx, _)| *idx)
+            .collect::<Vec<_>>();
+        match ties.len() {
+            0 => 0,
+            len => ties[self.rng.below(len as u64) as usize],
+        }
+    }
+
+    // an upstream request is written to the pool of `key`
+    pub fn on_sent(&mut self, key: &str) {
+        self.loads.entry(key.to_owned()).or_default().in_flight += 1;
+    }
+
+    // the request is answered or given up on after `latency`
+    pub fn on_done(&mut self, key: &str, latency: Duration) {
+        let load = self.loads.entry(key.to_owned()).or_default();
+        load.in_flight = load.in_flight.saturating_sub(1);
+        let ms = latency.as_secs_f64() * 1000.0;
+        load.ewma_ms = Some(match load.ewma_ms {
+            Some(v) => v + EWMA_ALPHA * (ms - v),
+            None => ms,
+        });
+    }
 }
 
 impl Deref for HttpForwardClient {
     type Target = BTreeMap<String, HttpConnClientPool>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.pools
     }
 }
 
 impl DerefMut for HttpForwardClient {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.pools
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::{BalancePolicy, RouteConfig, RouteOptions};
+
+    fn route(strategy: BalanceStrategy, weights: Vec<u64>) -> JsonrpcRoute {
+        JsonrpcRoute::from_config(RouteConfig::Full {
+            upstreams: vec![
+                "https://rpc-a.example".into(),
+                "https://rpc-b.example".into(),
+                "https://rpc-c.example".into(),
+            ],
+            options: RouteOptions {
+                balance: BalancePolicy { strategy, weights },
+                ..Default::default()
+            },
+        })
+        .unwrap()
+    }
+
+    fn picks<F>(
+        client: &mut HttpForwardClient,
+        route: &JsonrpcRoute,
+        n: usize,
+        available: F,
+    ) -> Vec<usize>
+    where
+        F: Fn(usize) -> bool,
+    {
+        (0..n)
+            .map(|_| client.pick("/eth", route, &available))
+            .collect()
+    }
+
+    fn all(_: usize) -> bool {
+        true
+    }
+
+    #[test]
+    fn round_robin_cycles() {
+        let route = route(BalanceStrategy::RoundRobin, vec![]);
+        let mut client = HttpForwardClient::new(Rng::new(7));
+        assert_eq!(picks(&mut client, &route, 6, all), vec![0, 1, 2, 0, 1, 2]);
+        // the turn skips upstreams out of rotation
+        assert_eq!(
+            picks(&mut client, &route, 4, |idx| idx != 1),
+            vec![0, 2, 0, 2]
+        );
+    }
+
+    #[test]
+    fn weighted_follows_weights() {
+        let route = route(BalanceStrategy::Weighted, vec![3, 1, 0]);
+        let mut client = HttpForwardClient::new(Rng::new(7));
+        let mut counts = [0usize; 3];
+        for idx in picks(&mut client, &route, 4000, all) {
+            counts[idx] += 1;
+        }
+        assert_eq!(counts[2], 0);
+        assert!((2800..3200).contains(&counts[0]), "{:?}", counts);
+        assert_eq!(counts[0] + counts[1], 4000);
+
+        // out of rotation, the others take its share
+        assert!(picks(&mut client, &route, 100, |idx| idx != 0)
+            .iter()
+            .all(|idx| *idx == 1));
+        // a zero weight one stands in when nothing else is in rotation
+        assert_eq!(picks(&mut client, &route, 3, |idx| idx == 2), vec![2, 2, 2]);
+    }
+
+    #[test]
+    fn least_outstanding_follows_in_flight() {
+        let route = route(BalanceStrategy::LeastOutstanding, vec![]);
+        let mut client = HttpForwardClient::new(Rng::new(7));
+        client.on_sent("/eth#0");
+        client.on_sent("/eth#0");
+        client.on_sent("/eth#1");
+        assert_eq!(picks(&mut client, &route, 3, all), vec![2, 2, 2]);
+
+        client.on_sent("/eth#2");
+        client.on_sent("/eth#2");
+        client.on_done("/eth#0", Duration::from_millis(10));
+        client.on_done("/eth#0", Duration::from_millis(10));
+        assert_eq!(picks(&mut client, &route, 3, all), vec![0, 0, 0]);
+        assert_eq!(picks(&mut client, &route, 3, |idx| idx != 0), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn ewma_prefers_faster() {
+        let route = route(BalanceStrategy::Ewma, vec![]);
+        let mut client = HttpForwardClient::new(Rng::new(7));
+        for (key, ms) in [("/eth#0", 120), ("/eth#1", 15)] {
+            client.on_sent(key);
+            client.on_done(key, Duration::from_millis(ms));
+        }
+        // never answered, tried first
+        assert_eq!(picks(&mut client, &route, 1, all), vec![2]);
+
+        client.on_sent("/eth#2");
+        client.on_done("/eth#2", Duration::from_millis(60));
+        assert_eq!(picks(&mut client, &route, 3, all), vec![1, 1, 1]);
+
+        // slower for a while, the average follows
+        for _ in 0..8 {
+            client.on_sent("/eth#1");
+            client.on_done("/eth#1", Duration::from_millis(200));
+        }
+        assert_eq!(picks(&mut client, &route, 3, all), vec![2, 2, 2]);
+        assert_eq!(picks(&mut client, &route, 3, |idx| idx != 2), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn none_in_rotation_picks_among_all() {
+        let route = route(BalanceStrategy::RoundRobin, vec![]);
+        let mut client = HttpForwardClient::new(Rng::new(7));
+        assert_eq!(picks(&mut client, &route, 3, |_| false), vec![0, 1, 2]);
     }
 }
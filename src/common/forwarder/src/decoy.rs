@@ -0,0 +1,51 @@
+use std::prelude::v1::*;
+
+use eth_types::H160;
+
+use crate::rng::Rng;
+
+// addresses to query alongside the real ones, so the upstream can't tell
+// which accounts a client actually asked for. decoys are drawn from the
+// configured addresses only, topped up with random ones. accounts of other
+// clients are never used, they would show up in the report of this one
+pub struct DecoyPool {
+    rng: Rng,
+}
+
+impl DecoyPool {
+    pub fn new(rng: Rng) -> Self {
+        Self { rng }
+    }
+
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    // `k` distinct addresses from `configured`, random ones if not enough
+    pub fn sample(&mut self, k: usize, configured: &[H160], exclude: &[H160]) -> Vec<H160> {
+        let mut candidates = vec![];
+        for addr in configured {
+            if !exclude.contains(addr) && !candidates.contains(addr) {
+                candidates.push(addr.clone());
+            }
+        }
+        self.rng.shuffle(&mut candidates);
+        candidates.truncate(k);
+        while candidates.len() < k {
+            let addr = self.synthetic();
+            if !exclude.contains(&addr) && !candidates.contains(&addr) {
+                candidates.push(addr);
+            }
+        }
+        candidates
+    }
+
+    fn synthetic(&mut self) -> H160 {
+        let mut data = [0u8; 20];
+        for chunk in data.chunks_mut(8) {
+            let v = self.rng.next_u64().to_be_bytes();
+            chunk.copy_from_slice(&v[..chunk.len()]);
+        }
+        H160::from_slice(&data)
+    }
+}
@@ -0,0 +1,231 @@
+use std::prelude::v1::*;
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use net_http::{
+    HttpConnClientPool, HttpConnError, HttpMethod, HttpRequestBuilder, TickResult, Uri,
+};
+
+use crate::health::PROBE_TIMEOUT;
+
+// a failed probe is tried again after at most this long
+const PROBE_RETRY: Duration = Duration::from_secs(30);
+
+// the address upstreams see our requests come from, as answered by a probe
+// endpoint (plain text, or json with an `ip` field). probed at start and
+// every `refresh` after, in the tick loop without blocking
+pub struct EgressIp {
+    probe: Option<Uri>,
+    refresh: Duration,
+    client: Option<HttpConnClientPool>,
+    ip: Option<String>,
+    next: Instant,
+    sent: Option<Instant>, // probe in flight
+}
+
+impl EgressIp {
+    // `None` probe leaves the address unknown
+    pub fn new(probe: Option<Uri>, refresh: Duration) -> Self {
+        Self {
+            probe,
+            refresh,
+            client: None,
+            ip: None,
+            next: Instant::now(),
+            sent: None,
+        }
+    }
+
+    // last address discovered
+    pub fn ip(&self) -> Option<&str> {
+        self.ip.as_deref()
+    }
+
+    fn fail(&mut self, now: Instant) {
+        self.client = None;
+        self.sent = None;
+        self.next = now + self.refresh.min(PROBE_RETRY);
+    }
+
+    pub fn tick(&mut self, tick: &mut TickResult) {
+        let uri = match &self.probe {
+            Some(v) => v.clone(),
+            None => return,
+        };
+        let now = Instant::now();
+        match self.sent {
+            Some(sent) if now.duration_since(sent) > PROBE_TIMEOUT => {
+                glog::error!("egress ip probe timeout");
+                self.fail(now);
+                return;
+            }
+            Some(_) => self.read_probe(tick, now),
+            None if now >= self.next => self.send_probe(tick, &uri, now),
+            None => {}
+        }
+    }
+
+    fn send_probe(&mut self, tick: &mut TickResult, uri: &Uri, now: Instant) {
+        if self.client.is_none() {
+            match HttpConnClientPool::new(1, uri) {
+                Ok(v) => self.client = Some(v),
+                Err(e) => {
+                    glog::error!("egress ip probe client fail: {:?}", e);
+                    self.fail(now);
+                    return;
+                }
+            }
+        }
+        let client = match &mut self.client {
+            Some(v) => v,
+            None => return,
+        };
+        let mut req = HttpRequestBuilder::new_ex(uri.clone(), None, |req| {
+            req.method(HttpMethod::Get);
+            req.header("Connection", "keep-alive");
+        });
+        tick.to_busy();
+        match client.write_request(0, &mut req) {
+            Ok(_) => self.sent = Some(now),
+            Err(HttpConnError::WouldBlock) => {}
+            Err(e) => {
+                glog::error!("egress ip probe write error: {:?}", e);
+                self.fail(now);
+            }
+        }
+    }
+
+    fn read_probe(&mut self, tick: &mut TickResult, now: Instant) {
+        let client = match &mut self.client {
+            Some(v) => v,
+            None => return,
+        };
+        let body = match client.read_response() {
+            Ok((_, resp)) => resp.body,
+            Err(HttpConnError::WouldBlock) => return,
+            Err(e) => {
+                glog::error!("egress ip probe read error: {:?}", e);
+                self.fail(now);
+                return;
+            }
+        };
+        tick.to_busy();
+        match parse_probe(&body) {
+            Some(ip) => {
+                if self.ip.as_deref() != Some(ip.as_str()) {
+                    glog::info!("egress ip: {}", ip);
+                }
+                self.ip = Some(ip);
+                self.sent = None;
+                self.next = now + self.refresh;
+            }
+            None => {
+                glog::error!("egress ip probe: unreadable response");
+                self.fail(now);
+            }
+        }
+    }
+}
+
+fn parse_probe(body: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(body);
+    let text = text.trim();
+    let ip = match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(serde_json::Value::Object(v)) => v.get("ip")?.as_str()?.to_owned(),
+        _ => text.to_owned(),
+    };
+    ip.parse::<IpAddr>().ok().map(|v| v.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // answers the probes with `bodies` in turn, over any connection
+    fn stand_in(bodies: Vec<&'static str>) -> Uri {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut bodies = bodies.into_iter();
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(v) => v,
+                    Err(_) => return,
+                };
+                let mut buf = vec![];
+                let mut chunk = [0u8; 1024];
+                loop {
+                    // a GET has no body, the head is the whole request
+                    if let Some(end) = buf.windows(4).position(|v| v == b"\r\n\r\n") {
+                        buf.drain(..end + 4);
+                        let body = match bodies.next() {
+                            Some(v) => v,
+                            None => return,
+                        };
+                        let resp = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        if stream.write_all(resp.as_bytes()).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    match stream.read(&mut chunk) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                    }
+                }
+            }
+        });
+        Uri::new(&format!("http://{}/", addr)).unwrap()
+    }
+
+    // tick until the probe answers `want`
+    fn wait_for(egress: &mut EgressIp, want: &str) {
+        let start = Instant::now();
+        while egress.ip() != Some(want) {
+            assert!(start.elapsed() < PROBE_TIMEOUT, "no {} probed", want);
+            let mut tick = TickResult::Idle;
+            egress.tick(&mut tick);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn probed_from_stand_in() {
+        let uri = stand_in(vec!["203.0.113.7\n", r#"{"ip": "2001:db8::1"}"#]);
+        // refreshed right away
+        let mut egress = EgressIp::new(Some(uri), Duration::from_millis(0));
+        assert_eq!(egress.ip(), None);
+        wait_for(&mut egress, "203.0.113.7");
+        wait_for(&mut egress, "2001:db8::1");
+    }
+
+    #[test]
+    fn no_probe_no_ip() {
+        let mut egress = EgressIp::new(None, Duration::from_millis(0));
+        let mut tick = TickResult::Idle;
+        egress.tick(&mut tick);
+        assert_eq!(egress.ip(), None);
+    }
+
+    #[test]
+    fn parse_probe_answers() {
+        assert_eq!(
+            parse_probe(b" 198.51.100.1\r\n").as_deref(),
+            Some("198.51.100.1")
+        );
+        assert_eq!(
+            parse_probe(br#"{"ip": "198.51.100.1"}"#).as_deref(),
+            Some("198.51.100.1")
+        );
+        assert_eq!(parse_probe(b"<html>blocked</html>"), None);
+        assert_eq!(parse_probe(br#"{"addr": "198.51.100.1"}"#), None);
+    }
+}
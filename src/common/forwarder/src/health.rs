@@ -0,0 +1,421 @@
+use std::prelude::v1::*;
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use jsonrpc::{Batchable, JsonrpcRawRequest, JsonrpcResponseRawResult};
+use net_http::{
+    HttpConnClientPool, HttpConnError, HttpMethod, HttpRequestBuilder, TickResult, Uri,
+};
+
+use crate::client;
+use crate::rng::Rng;
+use crate::route::{HeaderPolicy, HealthPolicy, JsonrpcRoute};
+use crate::utils;
+
+// a probe, of an upstream or of the egress ip, not answered by then counts
+// as failed
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Circuit {
+    Closed,
+    Open(Instant),             // out of rotation until then
+    HalfOpen(Option<Instant>), // trial request let through then
+}
+
+struct Upstream {
+    uri: Uri,
+    policy: HealthPolicy,
+    headers: HeaderPolicy,
+    circuit: Circuit,
+    probe_failures: usize, // in a row
+    live_errors: usize,    // in a row
+    client: Option<HttpConnClientPool>,
+    next_probe: Instant,
+    probe_sent: Option<Instant>, // probe in flight
+}
+
+// health of the upstreams of the routes seen, keyed like client pools. an
+// upstream failing too many probes or requests in a row is taken out of
+// rotation (open). after `open_ms`, or once a probe succeeds, one trial
+// request is let through (half-open), which puts it back or out again
+pub struct HealthChecker {
+    upstreams: BTreeMap<String, Upstream>,
+    rng: Rng,
+}
+
+impl HealthChecker {
+    pub fn new(rng: Rng) -> Self {
+        Self {
+            upstreams: BTreeMap::new(),
+            rng,
+        }
+    }
+
+    // start tracking the upstreams of the route, or follow the route as it
+    // is now. a changed policy applies right away, the circuit is kept
+    pub fn watch(&mut self, rpc_path: &str, route: &JsonrpcRoute) {
+        let now = Instant::now();
+        for (idx, uri) in route.upstreams.iter().enumerate() {
+            let key = client::pool_key(rpc_path, idx);
+            if let Some(up) = self.upstreams.get_mut(&key) {
+                up.uri = uri.clone();
+                if up.policy != route.options.health || up.headers != route.options.headers {
+                    glog::info!("upstream {} health policy changed", key);
+                    up.policy = route.options.health.clone();
+                    up.headers = route.options.headers.clone();
+                    // probed again under the new policy
+                    up.client = None;
+                    up.probe_sent = None;
+                    up.next_probe = now;
+                }
+                continue;
+            }
+            let up = Upstream {
+                uri: uri.clone(),
+                policy: route.options.health.clone(),
+                headers: route.options.headers.clone(),
+                circuit: Circuit::Closed,
+                probe_failures: 0,
+                live_errors: 0,
+                client: None,
+                next_probe: now,
+                probe_sent: None,
+            };
+            self.upstreams.insert(key, up);
+        }
+    }
+
+    // whether `allow` would let a request through, without taking the trial
+    pub fn available(&self, key: &str, now: Instant) -> bool {
+        match self.upstreams.get(key) {
+            Some(up) => up.available(now),
+            None => true,
+        }
+    }
+
+    // whether a request may go to the upstream now, untracked ones may
+    pub fn allow(&mut self, key: &str, now: Instant) -> bool {
+        let up = match self.upstreams.get_mut(key) {
+            Some(v) => v,
+            None => return true,
+        };
+        if !up.available(now) {
+            return false;
+        }
+        if up.circuit != Circuit::Closed {
+            up.circuit = Circuit::HalfOpen(Some(now));
+        }
+        true
+    }
+
+    // an upstream request answered
+    pub fn on_success(&mut self, key: &str) {
+        let up = match self.upstreams.get_mut(key) {
+            Some(v) => v,
+            None => return,
+        };
+        up.live_errors = 0;
+        if let Circuit::HalfOpen(_) = up.circuit {
+            glog::info!("upstream {} back in rotation", key);
+            up.circuit = Circuit::Closed;
+        }
+    }
+
+    // an upstream request not written, timed out, or answered with 429/5xx
+    pub fn on_failure(&mut self, key: &str, now: Instant) {
+        let up = match self.upstreams.get_mut(key) {
+            Some(v) => v,
+            None => return,
+        };
+        up.live_errors += 1;
+        match up.circuit {
+            Circuit::HalfOpen(_) => up.open(key, now, "trial request failed"),
+            Circuit::Closed if up.live_errors >= up.policy.live_errors => {
+                up.open(key, now, "too many request failures")
+            }
+            _ => {}
+        }
+    }
+
+    pub fn tick(&mut self, tick: &mut TickResult) {
+        let now = Instant::now();
+        for (key, up) in self.upstreams.iter_mut() {
+            if up.policy.interval_ms == 0 {
+                continue;
+            }
+            match up.probe_sent {
+                Some(sent) if now.duration_since(sent) > PROBE_TIMEOUT => {
+                    glog::error!("upstream {} probe timeout", key);
+                    up.probe_done(key, false, now);
+                }
+                Some(_) => up.read_probe(key, tick, now),
+                None if now >= up.next_probe => up.send_probe(key, &mut self.rng, tick, now),
+                None => {}
+            }
+        }
+    }
+}
+
+impl Upstream {
+    fn available(&self, now: Instant) -> bool {
+        let open = Duration::from_millis(self.policy.open_ms);
+        match self.circuit {
+            Circuit::Closed => true,
+            Circuit::Open(until) => now >= until,
+            // a trial lost somewhere doesn't keep it out forever
+            Circuit::HalfOpen(Some(trial)) => now.saturating_duration_since(trial) >= open,
+            Circuit::HalfOpen(None) => true,
+        }
+    }
+
+    fn open(&mut self, key: &str, now: Instant, why: &str) {
+        glog::warn!("upstream {} out of rotation: {}", key, why);
+        self.circuit = Circuit::Open(now + Duration::from_millis(self.policy.open_ms));
+    }
+
+    fn probe_done(&mut self, key: &str, ok: bool, now: Instant) {
+        self.probe_sent = None;
+        self.next_probe = now + Duration::from_millis(self.policy.interval_ms);
+        if ok {
+            self.probe_failures = 0;
+            if let Circuit::Open(_) = self.circuit {
+                glog::info!("upstream {} probed alive, half-open", key);
+                self.circuit = Circuit::HalfOpen(None);
+            }
+            return;
+        }
+        self.client = None;
+        self.probe_failures += 1;
+        if self.probe_failures >= self.policy.probe_failures {
+            self.open(key, now, "too many probe failures");
+        }
+    }
+
+    fn send_probe(&mut self, key: &str, rng: &mut Rng, tick: &mut TickResult, now: Instant) {
+        if self.client.is_none() {
+            match HttpConnClientPool::new(1, &self.uri) {
+                Ok(v) => self.client = Some(v),
+                Err(e) => {
+                    glog::error!("upstream {} probe client fail: {:?}", key, e);
+                    self.probe_done(key, false, now);
+                    return;
+                }
+            }
+        }
+        let id = rng.next_u64() >> 11;
+        let params: Vec<serde_json::Value> = vec![];
+        let body = match JsonrpcRawRequest::new(id, &self.policy.method, &params) {
+            Ok(v) => serde_json::to_vec(&v).unwrap(),
+            Err(e) => {
+                glog::error!("build upstream probe fail: {:?}", e);
+                self.probe_done(key, false, now);
+                return;
+            }
+        };
+        let ua = self.headers.user_agent(rng);
+        let fixed = self.headers.fixed();
+        let mut req = HttpRequestBuilder::new_ex(self.uri.clone(), Some(body), |req| {
+            req.method(HttpMethod::Post);
+            req.header("Content-Type", "application/json");
+            req.header("Connection", "keep-alive");
+            req.header("User-Agent", &ua);
+            for (k, v) in fixed {
+                req.header(&k, &v);
+            }
+        });
+        let client = match &mut self.client {
+            Some(v) => v,
+            None => return,
+        };
+        tick.to_busy();
+        match client.write_request(0, &mut req) {
+            Ok(_) => self.probe_sent = Some(now),
+            Err(HttpConnError::WouldBlock) => {}
+            Err(e) => {
+                glog::error!("upstream {} probe write error: {:?}", key, e);
+                self.probe_done(key, false, now);
+            }
+        }
+    }
+
+    fn read_probe(&mut self, key: &str, tick: &mut TickResult, now: Instant) {
+        let client = match &mut self.client {
+            Some(v) => v,
+            None => return,
+        };
+        let resp = match client.read_response() {
+            Ok((_, resp)) => resp,
+            Err(HttpConnError::WouldBlock) => return,
+            Err(e) => {
+                glog::error!("upstream {} probe read error: {:?}", key, e);
+                self.probe_done(key, false, now);
+                return;
+            }
+        };
+        tick.to_busy();
+        // any result will do, an error object means the node can't serve
+        let ok = !utils::is_retryable_http_response(&resp)
+            && matches!(
+                Batchable::<JsonrpcResponseRawResult>::parse(&resp.body),
+                Ok(Batchable::Single(JsonrpcResponseRawResult::Ok(_)))
+            );
+        if !ok {
+            glog::error!("upstream {} probe failed", key);
+        }
+        self.probe_done(key, ok, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::{RouteConfig, RouteOptions};
+
+    const KEY: &str = "/eth#0";
+
+    fn checker() -> HealthChecker {
+        let route = JsonrpcRoute::from_config(RouteConfig::Full {
+            upstreams: vec!["https://rpc-a.example".into()],
+            options: RouteOptions::default(),
+        })
+        .unwrap();
+        let mut checker = HealthChecker::new(Rng::new(7));
+        checker.watch("/eth", &route);
+        checker
+    }
+
+    fn circuit(checker: &HealthChecker) -> Circuit {
+        checker.upstreams[KEY].circuit
+    }
+
+    fn probe_done(checker: &mut HealthChecker, ok: bool, now: Instant) {
+        checker
+            .upstreams
+            .get_mut(KEY)
+            .unwrap()
+            .probe_done(KEY, ok, now);
+    }
+
+    fn ms(v: u64) -> Duration {
+        Duration::from_millis(v)
+    }
+
+    #[test]
+    fn failures_open_then_trial_closes() {
+        let mut checker = checker();
+        let t = Instant::now();
+        assert!(checker.allow(KEY, t));
+        // `live_errors` is 5
+        for _ in 0..4 {
+            checker.on_failure(KEY, t);
+        }
+        assert_eq!(circuit(&checker), Circuit::Closed);
+        checker.on_failure(KEY, t);
+        assert_eq!(circuit(&checker), Circuit::Open(t + ms(30_000)));
+        assert!(!checker.allow(KEY, t));
+        assert!(!checker.allow(KEY, t + ms(29_999)));
+
+        // one trial after `open_ms`
+        assert!(checker.allow(KEY, t + ms(30_000)));
+        assert_eq!(circuit(&checker), Circuit::HalfOpen(Some(t + ms(30_000))));
+        assert!(!checker.allow(KEY, t + ms(30_001)));
+        checker.on_success(KEY);
+        assert_eq!(circuit(&checker), Circuit::Closed);
+        assert!(checker.allow(KEY, t + ms(30_001)));
+    }
+
+    #[test]
+    fn failed_trial_opens_again() {
+        let mut checker = checker();
+        let t = Instant::now();
+        for _ in 0..5 {
+            checker.on_failure(KEY, t);
+        }
+        assert!(checker.allow(KEY, t + ms(30_000)));
+        checker.on_failure(KEY, t + ms(31_000));
+        assert_eq!(circuit(&checker), Circuit::Open(t + ms(61_000)));
+        assert!(!checker.allow(KEY, t + ms(60_999)));
+        assert!(checker.allow(KEY, t + ms(61_000)));
+    }
+
+    #[test]
+    fn lost_trial_lets_another_through() {
+        let mut checker = checker();
+        let t = Instant::now();
+        for _ in 0..5 {
+            checker.on_failure(KEY, t);
+        }
+        assert!(checker.allow(KEY, t + ms(30_000)));
+        // never answered
+        assert!(!checker.allow(KEY, t + ms(59_999)));
+        assert!(checker.allow(KEY, t + ms(60_000)));
+        assert_eq!(circuit(&checker), Circuit::HalfOpen(Some(t + ms(60_000))));
+    }
+
+    #[test]
+    fn probes_open_and_half_open() {
+        let mut checker = checker();
+        let t = Instant::now();
+        // `probe_failures` is 3, a success in between starts over
+        probe_done(&mut checker, false, t);
+        probe_done(&mut checker, false, t);
+        probe_done(&mut checker, true, t);
+        probe_done(&mut checker, false, t);
+        probe_done(&mut checker, false, t);
+        assert_eq!(circuit(&checker), Circuit::Closed);
+        probe_done(&mut checker, false, t);
+        assert_eq!(circuit(&checker), Circuit::Open(t + ms(30_000)));
+
+        // probed alive before `open_ms`, the trial goes right away
+        probe_done(&mut checker, true, t + ms(1_000));
+        assert_eq!(circuit(&checker), Circuit::HalfOpen(None));
+        assert!(checker.allow(KEY, t + ms(1_000)));
+        assert_eq!(circuit(&checker), Circuit::HalfOpen(Some(t + ms(1_000))));
+        checker.on_success(KEY);
+        assert_eq!(circuit(&checker), Circuit::Closed);
+    }
+
+    #[test]
+    fn available_takes_no_trial() {
+        let mut checker = checker();
+        let t = Instant::now();
+        for _ in 0..5 {
+            checker.on_failure(KEY, t);
+        }
+        assert!(!checker.available(KEY, t));
+        assert!(checker.available(KEY, t + ms(30_000)));
+        assert!(checker.available(KEY, t + ms(30_000)));
+        assert!(checker.allow(KEY, t + ms(30_000)));
+        assert!(!checker.available(KEY, t + ms(30_000)));
+        // untracked upstreams are always available
+        assert!(checker.available("/eth#1", t));
+    }
+
+    #[test]
+    fn watch_follows_policy_changes() {
+        let mut checker = checker();
+        let t = Instant::now();
+        checker.on_failure(KEY, t);
+        checker.on_failure(KEY, t);
+        assert_eq!(circuit(&checker), Circuit::Closed);
+
+        let route = JsonrpcRoute::from_config(RouteConfig::Full {
+            upstreams: vec!["https://rpc-a.example".into()],
+            options: RouteOptions {
+                health: HealthPolicy {
+                    live_errors: 3,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        })
+        .unwrap();
+        checker.watch("/eth", &route);
+        assert_eq!(checker.upstreams[KEY].policy.live_errors, 3);
+        // failures so far still count
+        checker.on_failure(KEY, t);
+        assert_eq!(circuit(&checker), Circuit::Open(t + ms(30_000)));
+    }
+}
@@ -1,7 +1,9 @@
+use std::collections::BTreeMap;
 use std::prelude::v1::*;
 use std::time::Duration;
 use std::{ops::DerefMut, time::Instant};
 
+use base::time;
 use base::trace::Alive;
 use jsonrpc::{Batchable, JsonrpcErrorObj, JsonrpcRawResponseFull, JsonrpcResponseRawResult};
 use net_http::{
@@ -14,8 +16,19 @@ use crate::types::{ResponseAndClose, ResponseBody};
 use crate::{client, utils};
 use crate::{
     client::HttpForwardClient,
-    sanitizer,
-    types::{JsonrpcForwardContext, JsonrpcForwardRequest, JsonrpcRequestMgr, JsonrpcResponseMgr},
+    decoy::DecoyPool,
+    egress::EgressIp,
+    health::HealthChecker,
+    mixer::{Mixer, SystemClock},
+    proxy::TrustedProxies,
+    rng::Rng,
+    route::JsonrpcRoute,
+    rules,
+    sanitizer::{self, ClientMetadata, RuleContext, RuleRegistry, Transform},
+    types::{
+        JsonrpcForwardContext, JsonrpcForwardRequest, JsonrpcRequestMgr, JsonrpcResponseMgr,
+        UPSTREAM_LOST_CODE, UPSTREAM_TIMEOUT_CODE,
+    },
     ForwarderError,
 };
 
@@ -28,13 +41,21 @@ pub struct JsonrpcForwarderConfig {
     pub ws_frame_size: usize,
     pub ws_keep_alive: Option<Duration>,
     pub ws_max_body_length: Option<usize>,
+    pub trusted_proxies: TrustedProxies, // may tell the client ip by headers
+    pub egress_probe: Option<Uri>,       // answers the ip upstreams see us at
+    pub egress_refresh: Duration,
 }
 
 pub trait JsonrpcForwarderHandler {
     // routes
-    fn get_http_uri(&self, key: &str) -> Option<Uri>;
+    fn get_http_route(&self, key: &str) -> Option<JsonrpcRoute>;
     fn get_ws_uri(&self, key: &str) -> Option<Uri>;
 
+    // rules routes pick from by name
+    fn sanitizer_rules(&self) -> RuleRegistry {
+        RuleRegistry::with_defaults()
+    }
+
     // hooks
     fn on_http_request(&mut self, ctx: JsonrpcForwardContext, req: &JsonrpcForwardRequest);
     fn on_new_ws_conn(
@@ -142,13 +163,23 @@ impl<H: JsonrpcForwarderHandler> JsonrpcForwarder<H> {
                 max_idle_secs: None,
             };
 
+            let rules = handler.sanitizer_rules();
+            let egress = EgressIp::new(cfg.egress_probe.clone(), cfg.egress_refresh);
             let srv_handler = ServerHandler {
                 alive,
                 cfg,
                 handler,
+                rules,
                 http_reqs: JsonrpcRequestMgr::new(),
                 http_responses: JsonrpcResponseMgr::new(),
-                http_client: client::HttpForwardClient::new(),
+                http_client: client::HttpForwardClient::new(Rng::from_entropy()),
+                http_sends: BTreeMap::new(),
+                send_id: 0,
+                mixer: Mixer::new(SystemClock, Rng::from_entropy()),
+                decoys: DecoyPool::new(Rng::from_entropy()),
+                covers: BTreeMap::new(),
+                egress,
+                health: HealthChecker::new(Rng::from_entropy()),
             };
             HttpWsServer::new(server_cfg, srv_handler)
                 .map_err(|err| ForwarderError::ListenError(err))
@@ -165,9 +196,17 @@ struct ServerHandler<H: JsonrpcForwarderHandler> {
     alive: Alive, // fork to use
     cfg: JsonrpcForwarderConfig,
     handler: H,
+    rules: RuleRegistry,
     http_reqs: JsonrpcRequestMgr,
     http_responses: JsonrpcResponseMgr,
     http_client: HttpForwardClient,
+    http_sends: BTreeMap<usize, (usize, usize)>, // send_id -> (req_id, part)
+    send_id: usize,
+    mixer: Mixer,
+    decoys: DecoyPool,
+    covers: BTreeMap<String, (JsonrpcRoute, Instant)>, // rpc_path -> (route, next cover query)
+    egress: EgressIp,
+    health: HealthChecker,
 }
 
 impl<H: JsonrpcForwarderHandler> ServerHandler<H> {
@@ -184,114 +223,361 @@ impl<H: JsonrpcForwarderHandler> ServerHandler<H> {
 
     fn tick_http_reqs(&mut self, tick: &mut TickResult, http_conns: &mut HttpServerConns) {
         let mut remove_req = vec![];
-        let mut close_conn = vec![];
+        let mut answered = vec![];
+        let now = Instant::now();
         for (req_id, req) in self.http_reqs.deref_mut().iter_mut() {
-            let key = req.rpc_path.clone();
-            match req.last_send {
-                Some(send) => {
-                    let e = send.elapsed();
-                    if e > Duration::from_secs(20) {
-                        glog::error!(
-                            "[{}] request timeout={:?}: req={}, conn={}",
-                            key,
-                            e,
-                            req_id,
-                            req.conn_id
-                        );
-                        remove_req.push(*req_id);
-                        close_conn.push(req.conn_id);
-                    }
+            let mut timeout = false;
+            for (part, e) in req.expired(now) {
+                glog::error!(
+                    "[{}] request timeout={:?}: req={}, part={}, conn={}",
+                    req.rpc_path,
+                    e,
+                    req_id,
+                    part,
+                    req.conn_id
+                );
+                let (key, _) = req.upstream(part);
+                self.health.on_failure(&key, now);
+                let sent_at = req.in_flight(part);
+                // a late answer to this attempt is discarded, whether the part
+                // fails over or the request fails
+                if let Some(send_id) = req.parts[part].send_id {
+                    self.http_sends.remove(&send_id);
                 }
-                _ => {
-                    let client = match self.http_client.get_or_new(key, &req.remote_uri) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            glog::error!("get http_client fail: {:?}", e);
-                            continue;
-                        }
-                    };
-                    tick.to_busy();
-                    // mark send time earlier the better, and `get_or_new` is not likely to fail
-                    req.last_send = Some(Instant::now());
-                    let mut http_req = req.build_http_request();
-                    match client.write_request(*req_id, &mut http_req) {
-                        Ok(_) => {}
-                        Err(HttpConnError::WouldBlock) => continue,
-                        Err(e) => {
-                            glog::error!("http_client write error: {:?}", e);
-                            continue;
-                        }
-                    }
+                if !req.failover(part, now) {
+                    timeout = true;
+                    break;
+                }
+                if let Some(sent_at) = sent_at {
+                    self.http_client
+                        .on_done(&key, now.saturating_duration_since(sent_at));
                 }
             }
+            if timeout {
+                remove_req.push(*req_id);
+                continue;
+            }
+            if req.is_answered() {
+                // nothing left to send upstream
+                answered.push(*req_id);
+            }
+        }
+
+        // parts of all clients, in the order the mixer releases them
+        let mut broken = vec![];
+        for (req_id, part) in self.mixer.due(&self.http_reqs) {
+            if remove_req.contains(&req_id) {
+                continue;
+            }
+            let req = match self.http_reqs.get_mut(&req_id) {
+                Some(v) => v,
+                None => continue,
+            };
+            if !self.health.allow(&req.upstream(part).0, now) {
+                let n = req.route.upstreams.len();
+                let cur = req.parts[part].upstream;
+                let healthy = (1..n).map(|i| (cur + i) % n).find(|idx| {
+                    self.health
+                        .allow(&client::pool_key(&req.rpc_path, *idx), now)
+                });
+                // pinned, or every upstream is out of rotation, try it anyway
+                if let Some(idx) = healthy {
+                    req.reroute(part, idx);
+                }
+            }
+            let (key, uri) = req.upstream(part);
+            if broken.contains(&key) {
+                // sent on a fresh connection next tick
+                continue;
+            }
+            let client = match self.http_client.get_or_new(key.clone(), uri) {
+                Ok(v) => v,
+                Err(e) => {
+                    glog::error!("get http_client fail: {:?}", e);
+                    self.health.on_failure(&key, now);
+                    // left to the connect timeout if it can't fail over
+                    req.failover(part, Instant::now());
+                    continue;
+                }
+            };
+            tick.to_busy();
+            let mut http_req = req.build_http_request(part);
+            match client.write_request(self.send_id, &mut http_req) {
+                Ok(_) => {
+                    self.http_client.on_sent(&key);
+                    req.parts[part].sent_at = Some(Instant::now());
+                    req.parts[part].send_id = Some(self.send_id);
+                    self.http_sends.insert(self.send_id, (req_id, part));
+                    self.send_id = self.send_id.wrapping_add(1);
+                }
+                Err(HttpConnError::WouldBlock) => continue,
+                Err(e) => {
+                    glog::error!("http_client_conn[{}] write error: {:?}", key, e);
+                    self.health.on_failure(&key, now);
+                    req.failover(part, Instant::now());
+                    broken.push(key);
+                    continue;
+                }
+            }
+        }
+        for key in broken {
+            // built anew by the next request, like on a read error
+            self.http_client.remove(&key);
+            self.recover_lost_sends(&key, http_conns);
         }
         for req_id in remove_req {
-            self.http_reqs.pop(&req_id);
+            let err = JsonrpcErrorObj::error(UPSTREAM_TIMEOUT_CODE, "upstream timeout".into());
+            self.fail_http_req(req_id, err, http_conns);
         }
-        for conn_id in close_conn {
-            http_conns.remove_conn(conn_id);
+        for req_id in answered {
+            self.finish_http_req(req_id, http_conns);
         }
     }
 
     fn tick_http_recv_remote(&mut self, tick: &mut TickResult, http_conns: &mut HttpServerConns) {
+        let mut answered = vec![];
+        let mut done = vec![]; // (pool key, latency)
+        let mut broken = vec![];
         for (key, conn_pool) in self.http_client.deref_mut().iter_mut() {
             loop {
-                let (req_id, http_response) = match conn_pool.read_response() {
+                let (send_id, http_response) = match conn_pool.read_response() {
                     Ok(v) => v,
                     Err(HttpConnError::WouldBlock) => break,
                     Err(e) => {
                         glog::error!("http_client_conn[{}] read error: {:?}", key, e);
-                        // client will be re-built, but all reqs sent via this one get lost
-                        // close all conns of such reqs is most straightforward, OR we can
-                        // retry reqs with some timeout at `tick_http_reqs`, and finally
-                        // close if it fails, too
+                        self.health.on_failure(key, Instant::now());
+                        broken.push(key.clone());
                         break;
                     }
                 };
 
-                match self.http_reqs.pop(&req_id) {
+                let (req_id, part) = match self.http_sends.remove(&send_id) {
+                    Some(v) => v,
+                    None => {
+                        // its request failed or timed out meanwhile
+                        glog::warn!("discard response of send[{}], given up on", send_id);
+                        continue;
+                    }
+                };
+                match self.http_reqs.get_mut(&req_id) {
+                    Some(req) if req.parts.get(part).and_then(|v| v.send_id) != Some(send_id) => {
+                        // the part failed over meanwhile
+                        glog::warn!("discard stale response of req[{}]", req_id);
+                    }
                     Some(req) => {
                         tick.to_busy();
-
-                        let response_full = match Batchable::parse(&http_response.body) {
-                            Ok(bat) => {
-                                let rewritten = req.sr.rewrite_response(bat);
-                                rewritten.map(|res| match res {
-                                    JsonrpcResponseRawResult::Ok(v) => JsonrpcRawResponseFull {
-                                        jsonrpc: v.jsonrpc,
-                                        result: Some(v.result),
-                                        error: None,
-                                        id: Some(v.id),
-                                    },
-                                    JsonrpcResponseRawResult::Err(v) => JsonrpcRawResponseFull {
-                                        jsonrpc: v.jsonrpc,
-                                        result: None,
-                                        error: Some(v.error),
-                                        id: v.id,
-                                    },
-                                })
-                            }
-                            Err(e) => {
-                                let err = JsonrpcErrorObj::error(-32700, e.to_string());
-                                let err_resp = JsonrpcRawResponseFull::err(err, None);
-                                Batchable::Single(err_resp)
-                            }
-                        };
-                        let body = serde_json::to_vec(&response_full).unwrap();
-                        let data = utils::create_http_jsonrpc_plain_response(body);
-                        if let Err(e) = http_conns.write_to(req.conn_id, &data) {
-                            glog::error!("http_conn[{}] write error: {:?}", req.conn_id, e);
-                            http_conns.remove_conn(req.conn_id);
+                        if let Some(sent_at) = req.in_flight(part) {
+                            done.push((key.clone(), sent_at.elapsed()));
+                        }
+                        let retryable = utils::is_retryable_http_response(&http_response);
+                        match retryable {
+                            true => self.health.on_failure(key, Instant::now()),
+                            false => self.health.on_success(key),
+                        }
+                        if retryable && req.failover(part, Instant::now()) {
+                            glog::warn!(
+                                "[{}] upstream {} failed, fail over: req={}, part={}",
+                                req.rpc_path,
+                                key,
+                                req_id,
+                                part
+                            );
+                            continue;
+                        }
+                        let resp = Batchable::parse(&http_response.body).map_err(|e| e.to_string());
+                        if let Some(part) = req.parts.get_mut(part) {
+                            part.response = Some(resp);
+                        }
+                        if req.is_answered() {
+                            answered.push(req_id);
                         }
-                        // mark close after response
-                        http_conns.close_conn(req.conn_id);
                     }
                     _ => {
-                        glog::error!("req[{}] not found in http_reqs", req_id);
+                        // timed out and answered already
+                        glog::warn!("discard late response of req[{}]", req_id);
                     }
                 }
             }
         }
+        for (key, latency) in done {
+            self.http_client.on_done(&key, latency);
+        }
+        for key in broken {
+            // built anew by the next request
+            self.http_client.remove(&key);
+            self.recover_lost_sends(&key, http_conns);
+        }
+        for req_id in answered {
+            self.finish_http_req(req_id, http_conns);
+        }
+    }
+
+    // requests written to the broken pool of `key` are never answered.
+    // idempotent ones are sent again on a fresh connection, the client of any
+    // other is answered with an error right away
+    fn recover_lost_sends(&mut self, key: &str, http_conns: &mut HttpServerConns) {
+        let now = Instant::now();
+        let http_reqs = &self.http_reqs;
+        let lost = self
+            .http_sends
+            .iter()
+            .filter(|(send_id, (req_id, part))| match http_reqs.get(req_id) {
+                Some(req) => {
+                    req.in_flight(*part).is_some()
+                        && req.parts[*part].send_id == Some(**send_id)
+                        && req.upstream(*part).0 == key
+                }
+                None => false,
+            })
+            .map(|(send_id, v)| (*send_id, *v))
+            .collect::<Vec<_>>();
+
+        let mut failed = vec![];
+        for (send_id, (req_id, part)) in lost {
+            self.http_sends.remove(&send_id);
+            let req = match self.http_reqs.get_mut(&req_id) {
+                Some(v) => v,
+                None => continue,
+            };
+            if let Some(sent_at) = req.in_flight(part) {
+                self.http_client
+                    .on_done(key, now.saturating_duration_since(sent_at));
+            }
+            if req.resend(part, now) {
+                glog::warn!(
+                    "[{}] upstream {} conn broken, resend: req={}, part={}",
+                    req.rpc_path,
+                    key,
+                    req_id,
+                    part
+                );
+                continue;
+            }
+            req.parts[part].send_id = None;
+            if !failed.contains(&req_id) {
+                failed.push(req_id);
+            }
+        }
+        for req_id in failed {
+            glog::error!("req[{}] lost with upstream {}", req_id, key);
+            let err = JsonrpcErrorObj::error(UPSTREAM_LOST_CODE, "upstream connection lost".into());
+            self.fail_http_req(req_id, err, http_conns);
+        }
+    }
+
+    // answer the client with `err`, late responses of the request find nothing
+    // and are discarded
+    fn fail_http_req(
+        &mut self,
+        req_id: usize,
+        err: JsonrpcErrorObj,
+        http_conns: &mut HttpServerConns,
+    ) {
+        let req = match self.http_reqs.pop(&req_id) {
+            Some(v) => v,
+            None => return,
+        };
+        self.http_sends.retain(|_, (id, _)| *id != req_id);
+        let now = Instant::now();
+        for part in 0..req.parts.len() {
+            if let Some(sent_at) = req.in_flight(part) {
+                let (key, _) = req.upstream(part);
+                self.http_client
+                    .on_done(&key, now.saturating_duration_since(sent_at));
+            }
+        }
+        if req.cover {
+            return;
+        }
+        write_http_response(http_conns, req.conn_id, &req.error_response(err));
+    }
+
+    // all parts of the request are answered, respond to the client, or go on
+    // with the actual requests if blocks were being resolved
+    fn finish_http_req(&mut self, req_id: usize, http_conns: &mut HttpServerConns) {
+        match self.http_reqs.get_mut(&req_id) {
+            Some(req) => match req.take_response() {
+                Some(resp) if req.sr.is_pinning() => {
+                    // blocks resolved, send the actual requests
+                    req.sr
+                        .pin_blocks(resp.ok(), req.route.options.sub_retries());
+                    req.split(&mut self.mixer);
+                    return;
+                }
+                Some(resp) => {
+                    if let Err(e) = &resp {
+                        glog::error!("[{}] parse upstream response fail: {}", req.rpc_path, e);
+                    }
+                    req.sr.collect_results(resp.ok());
+                    let budget = req.route.options.sub_retries();
+                    if req.sr.retry_failed(&self.rules, budget) {
+                        req.split(&mut self.mixer);
+                        return;
+                    }
+                }
+                None => return,
+            },
+            None => return,
+        };
+        let mut req = match self.http_reqs.pop(&req_id) {
+            Some(v) => v,
+            None => return,
+        };
+        if req.cover {
+            return;
+        }
+
+        let rewritten = req.sr.rewrite_response(&self.rules);
+        let response_full = rewritten.map(|res| match res {
+            JsonrpcResponseRawResult::Ok(v) => JsonrpcRawResponseFull {
+                jsonrpc: v.jsonrpc,
+                result: Some(v.result),
+                error: None,
+                id: Some(v.id),
+            },
+            JsonrpcResponseRawResult::Err(v) => JsonrpcRawResponseFull {
+                jsonrpc: v.jsonrpc,
+                result: None,
+                error: Some(v.error),
+                id: v.id,
+            },
+        });
+        write_http_response(http_conns, req.conn_id, &response_full);
+    }
+
+    // background queries at each route's cover rate, answered to nobody
+    fn tick_cover_traffic(&mut self, tick: &mut TickResult) {
+        let now = self.mixer.now();
+        for (rpc_path, (route, next)) in self.covers.iter_mut() {
+            if *next > now {
+                continue;
+            }
+            // uniform within [interval/2, interval*3/2]
+            let interval = Duration::from_millis(route.options.cover_interval_ms);
+            *next = now + interval / 2 + self.mixer.delay(interval);
+
+            let configured = route.options.decoy_addresses();
+            let addr = match self.decoys.sample(1, &configured, &[]).pop() {
+                Some(v) => v,
+                None => continue,
+            };
+            let ua = route.options.headers.user_agent(self.decoys.rng());
+            let sr = match rules::cover_request(&addr, &route.options.headers, &ua) {
+                Some(v) => v,
+                None => continue,
+            };
+            tick.to_busy();
+            let mut req =
+                JsonrpcForwardRequest::new(usize::MAX, rpc_path.clone(), route.clone(), sr);
+            req.cover = true;
+            req.ua = ua;
+            let health = &self.health;
+            req.base = self.http_client.pick(rpc_path, route, |idx| {
+                health.available(&client::pool_key(rpc_path, idx), now)
+            });
+            req.split(&mut self.mixer);
+            self.http_reqs.push(req);
+        }
     }
 
     // relay results is sent in `tick_http_recv_remote .. write_to()`
@@ -359,7 +645,7 @@ impl<H: JsonrpcForwarderHandler> HttpWsServerHandler for ServerHandler<H> {
                 return;
             }
         };
-        let remote_uri = match self.handler.get_http_uri(&rpc_path) {
+        let route = match self.handler.get_http_route(&rpc_path) {
             Some(v) => v,
             _ => {
                 self.http_responses.add_single_error_msg_closed(
@@ -391,20 +677,46 @@ impl<H: JsonrpcForwarderHandler> HttpWsServerHandler for ServerHandler<H> {
             }
         }
 
-        let mut sr = sanitizer::SanitizedRequest::new(req_body);
-        // account relationship
-        sr = sanitizer::protect_account_relationship(sr);
-        // metadata
-        sr = sanitizer::protect_metadata(sr, ctx, &mut req);
+        let client = ClientMetadata::from_http(ctx, &mut req, &self.cfg.trusted_proxies);
+        // the one sent upstream is the one reported
+        let ua = route.options.headers.user_agent(self.decoys.rng());
+        let mut rule_ctx = RuleContext {
+            route: &route,
+            rpc_path,
+            client: Some(&client),
+            decoys: &mut self.decoys,
+            egress_ip: self.egress.ip(),
+            ua: &ua,
+        };
+        let sr = sanitizer::SanitizedRequest::from_raw(req_body, req.body());
+        let mut sr = self
+            .rules
+            .sanitize(sr, &route.options.rules(), &mut rule_ctx);
+        let plaintext = route.plaintext_upstreams();
+        if plaintext > 0 {
+            sr.tr.push(Transform::PlaintextUpstream {
+                upstreams: plaintext,
+                time: time::Date::from(time::now()).to_string(),
+            });
+        }
+
+        self.health.watch(rpc_path, &route);
+        if route.options.cover_interval_ms > 0 && !self.covers.contains_key(rpc_path) {
+            let interval = Duration::from_millis(route.options.cover_interval_ms);
+            let next = self.mixer.now() + interval;
+            self.covers
+                .insert(rpc_path.to_owned(), (route.clone(), next));
+        }
 
         let fwd_ctx = JsonrpcForwardContext { token };
-        let fwd_req = JsonrpcForwardRequest {
-            conn_id: ctx.conn_id,
-            rpc_path: rpc_path.to_owned(),
-            remote_uri,
-            sr,
-            last_send: None,
-        };
+        let mut fwd_req = JsonrpcForwardRequest::new(ctx.conn_id, rpc_path.to_owned(), route, sr);
+        fwd_req.ua = ua;
+        let health = &self.health;
+        let now = Instant::now();
+        fwd_req.base = self.http_client.pick(rpc_path, &fwd_req.route, |idx| {
+            health.available(&client::pool_key(rpc_path, idx), now)
+        });
+        fwd_req.split(&mut self.mixer);
 
         self.handler.on_http_request(fwd_ctx, &fwd_req);
 
@@ -431,6 +743,9 @@ impl<H: JsonrpcForwarderHandler> HttpWsServerHandler for ServerHandler<H> {
         ws_conns: &mut WsServerConns,
     ) -> TickResult {
         let mut tick = TickResult::Idle;
+        self.egress.tick(&mut tick);
+        self.health.tick(&mut tick);
+        self.tick_cover_traffic(&mut tick);
         self.tick_http_reqs(&mut tick, http_conns);
         self.tick_http_recv_remote(&mut tick, http_conns);
         self.tick_http_send_response(&mut tick, http_conns);
@@ -439,6 +754,22 @@ impl<H: JsonrpcForwarderHandler> HttpWsServerHandler for ServerHandler<H> {
     }
 }
 
+// the only response of the connection, closed after it
+fn write_http_response(
+    http_conns: &mut HttpServerConns,
+    conn_id: usize,
+    resp: &Batchable<JsonrpcRawResponseFull>,
+) {
+    let body = serde_json::to_vec(resp).unwrap();
+    let data = utils::create_http_jsonrpc_plain_response(body);
+    if let Err(e) = http_conns.write_to(conn_id, &data) {
+        glog::error!("http_conn[{}] write error: {:?}", conn_id, e);
+        http_conns.remove_conn(conn_id);
+    }
+    // mark close after response
+    http_conns.close_conn(conn_id);
+}
+
 // "/{rpc_network}/{token}/.."
 fn extract_path_and_token(path: &str) -> (Option<&str>, Option<&str>) {
     let mut it = path.split("/");
@@ -14,10 +14,50 @@ pub use error::*;
 mod types;
 pub use types::{
     JsonrpcForwardContext, JsonrpcForwardRequest, JsonrpcRequestMgr, JsonrpcResponseMgr,
+    UpstreamPart, BLOCK_UNRESOLVED_CODE, UPSTREAM_LOST_CODE, UPSTREAM_TIMEOUT_CODE,
 };
 
+mod route;
+pub use route::{
+    BalancePolicy, BalanceStrategy, ContractRegistry, HeaderPolicy, HealthPolicy, JsonrpcRoute,
+    RouteConfig, RouteOptions, SplitPolicy, TimeoutPolicy, UserAgentPolicy,
+};
+
+mod rng;
+pub use rng::Rng;
+
+mod mixer;
+pub use mixer::{Clock, Mixer, SystemClock};
+
+mod decoy;
+pub use decoy::DecoyPool;
+
+mod normalize;
+
+mod abi_rule;
+pub use abi_rule::{AbiOutput, AbiRule, AbiRuleConfig, SubCallConfig};
+
 mod sol;
 
+mod multicall;
+
+mod block;
+pub use block::BlockParam;
+
 pub mod sanitizer;
 
+mod proxy;
+pub use proxy::{Cidr, TrustedProxies, DEFAULT_CLIENT_IP_HEADERS};
+
+mod egress;
+pub use egress::EgressIp;
+
+mod health;
+pub use health::HealthChecker;
+
+mod rules;
+
+mod logs;
+pub use logs::WsLogSplitter;
+
 mod client;
@@ -0,0 +1,518 @@
+use std::prelude::v1::*;
+
+use std::collections::{BTreeMap, VecDeque};
+
+use eth_types::U256;
+use jsonrpc::{Batchable, JsonrpcRawRequest, JsonrpcRawResponseFull, JsonrpcResponseRawResult};
+use serde_json::{Map, Value};
+
+use crate::rng::{self, Rng};
+use crate::sanitizer::sub_request_error;
+use crate::utils;
+
+// a filter of several addresses, or of topics holding several padded wallet
+// addresses, tells the upstream these belong together. it's broken into
+// filters of one address and one owner each, other owner positions are
+// widened to any, and the merged logs are matched against the original
+// filter again
+
+// more would flood the upstream, such filters are sent as is
+const MAX_SPLIT: usize = 16;
+
+// logs remembered per subscription to drop the ones delivered twice
+const SEEN_LOGS: usize = 1024;
+
+// a topic holding a 20 bytes address
+fn is_padded_address(s: &str) -> bool {
+    s.len() == 66
+        && s.starts_with("0x")
+        && s[2..].chars().all(|c| c.is_ascii_hexdigit())
+        && s[2..26].chars().all(|c| c == '0')
+        && !s[26..].chars().all(|c| c == '0')
+}
+
+fn topic_words(v: &Value) -> Vec<&str> {
+    match v {
+        Value::String(s) => vec![s.as_str()],
+        Value::Array(vs) => vs.iter().filter_map(|v| v.as_str()).collect(),
+        _ => vec![],
+    }
+}
+
+// filters that together match at least what `filter` matches, `None` if
+// there's nothing to break down
+pub fn split_filter(filter: &Value) -> Option<Vec<Value>> {
+    let obj = filter.as_object()?;
+    let mut addresses = vec![];
+    if let Some(Value::Array(vs)) = obj.get("address") {
+        for v in vs {
+            if !addresses.contains(v) {
+                addresses.push(v.clone());
+            }
+        }
+    }
+    if addresses.len() < 2 {
+        addresses.clear();
+    }
+
+    let topics = match obj.get("topics") {
+        Some(Value::Array(vs)) => vs.clone(),
+        _ => vec![],
+    };
+    // (position, topic) choices covering every owner position
+    let mut owners = vec![];
+    let mut owner_positions = vec![];
+    let mut distinct = Vec::<String>::new();
+    for (pos, t) in topics.iter().enumerate() {
+        let words = topic_words(t);
+        let (padded, others): (Vec<&str>, Vec<&str>) =
+            words.into_iter().partition(|v| is_padded_address(v));
+        if padded.is_empty() {
+            continue;
+        }
+        owner_positions.push(pos);
+        for w in padded {
+            let w = w.to_ascii_lowercase();
+            if !distinct.contains(&w) {
+                distinct.push(w.clone());
+            }
+            owners.push((pos, Value::String(w)));
+        }
+        if !others.is_empty() {
+            let others = others.into_iter().map(|v| Value::String(v.into()));
+            owners.push((pos, Value::Array(others.collect())));
+        }
+    }
+    if distinct.len() < 2 {
+        owners.clear();
+    }
+
+    if addresses.is_empty() && owners.is_empty() {
+        return None;
+    }
+    let count = addresses.len().max(1) * owners.len().max(1);
+    if count > MAX_SPLIT {
+        glog::warn!("split log filter abort: {} queries", count);
+        return None;
+    }
+
+    let address_choices = match addresses.is_empty() {
+        true => vec![None],
+        false => addresses.into_iter().map(Some).collect(),
+    };
+    let owner_choices = match owners.is_empty() {
+        true => vec![None],
+        false => owners.into_iter().map(Some).collect(),
+    };
+    let mut filters = vec![];
+    for address in &address_choices {
+        for owner in &owner_choices {
+            let mut sub = obj.clone();
+            if let Some(address) = address {
+                sub.insert("address".into(), address.clone());
+            }
+            if let Some((pos, topic)) = owner {
+                let mut topics = topics.clone();
+                for p in &owner_positions {
+                    topics[*p] = Value::Null;
+                }
+                topics[*pos] = topic.clone();
+                sub.insert("topics".into(), Value::Array(topics));
+            }
+            filters.push(Value::Object(sub));
+        }
+    }
+    Some(filters)
+}
+
+fn eq_hex(a: &Value, b: &str) -> bool {
+    a.as_str()
+        .map(|v| v.eq_ignore_ascii_case(b))
+        .unwrap_or(false)
+}
+
+// whether `log` is one `filter` asks for, blocks aside
+fn log_matches(filter: &Map<String, Value>, log: &Value) -> bool {
+    let address = log.get("address").and_then(|v| v.as_str()).unwrap_or("");
+    match filter.get("address") {
+        Some(Value::String(v)) if !v.eq_ignore_ascii_case(address) => return false,
+        Some(Value::Array(vs)) if !vs.is_empty() && !vs.iter().any(|v| eq_hex(v, address)) => {
+            return false
+        }
+        _ => {}
+    }
+    let log_topics = match log.get("topics") {
+        Some(Value::Array(vs)) => vs.as_slice(),
+        _ => &[],
+    };
+    if let Some(Value::Array(topics)) = filter.get("topics") {
+        for (pos, t) in topics.iter().enumerate() {
+            let allowed = match t {
+                Value::Null => continue,
+                Value::Array(vs) if vs.is_empty() || vs.iter().any(|v| v.is_null()) => continue,
+                t => topic_words(t),
+            };
+            let topic = log_topics.get(pos).and_then(|v| v.as_str()).unwrap_or("");
+            if !allowed.iter().any(|v| v.eq_ignore_ascii_case(topic)) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn quantity(v: Option<&Value>) -> U256 {
+    v.and_then(|v| v.as_str())
+        .and_then(|v| v.strip_prefix("0x"))
+        .and_then(|v| U256::from_str_radix(v, 16).ok())
+        .unwrap_or_default()
+}
+
+// identifies one log of one block, removed ones apart
+fn log_key(log: &Value) -> String {
+    let field = |k: &str| {
+        log.get(k)
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_ascii_lowercase()
+    };
+    let removed = log
+        .get("removed")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    format!(
+        "{}{}{}{}",
+        field("blockHash"),
+        field("transactionHash"),
+        field("logIndex"),
+        removed
+    )
+}
+
+// logs of the split filters as the original filter would have returned them:
+// matched, without duplicates, by (blockNumber, logIndex)
+pub fn merge_logs(filter: &Value, results: Vec<Vec<Value>>) -> Vec<Value> {
+    let empty = Map::new();
+    let filter = filter.as_object().unwrap_or(&empty);
+    let mut seen = BTreeMap::new();
+    for log in results.into_iter().flatten() {
+        if log_matches(filter, &log) {
+            seen.entry(log_key(&log)).or_insert(log);
+        }
+    }
+    let mut logs = seen.into_iter().map(|(_, v)| v).collect::<Vec<_>>();
+    logs.sort_by_key(|v| (quantity(v.get("blockNumber")), quantity(v.get("logIndex"))));
+    logs
+}
+
+// accounts a filter is about, addresses and owners in topics
+pub fn filter_accounts(filter: &Value) -> Vec<String> {
+    let mut accounts = vec![];
+    let addresses = filter.get("address").map(topic_words).unwrap_or_default();
+    for v in addresses {
+        accounts.push(v.to_ascii_lowercase());
+    }
+    if let Some(Value::Array(topics)) = filter.get("topics") {
+        for w in topics.iter().flat_map(topic_words) {
+            if is_padded_address(w) {
+                accounts.push(String::from("0x") + &w[26..].to_ascii_lowercase());
+            }
+        }
+    }
+    let mut unique = vec![];
+    for v in accounts {
+        if !unique.contains(&v) {
+            unique.push(v);
+        }
+    }
+    unique
+}
+
+// the filter object of `eth_getLogs`/`eth_newFilter`, and of
+// `eth_subscribe("logs", ..)`
+pub fn request_filter(req: &JsonrpcRawRequest) -> Option<Value> {
+    match req.method.as_str() {
+        "eth_getLogs" | "eth_newFilter" => utils::get_jsonrpc_param(req, 0),
+        "eth_subscribe" => match utils::get_jsonrpc_param(req, 0)?.as_str() {
+            Some("logs") => utils::get_jsonrpc_param(req, 1).or(Some(Value::Object(Map::new()))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+enum WsGroupKind {
+    Logs(Value),      // `eth_getLogs` of the original filter
+    Subscribe(Value), // `eth_subscribe("logs")` of the original filter
+    Unsubscribe,
+}
+
+// upstream requests standing for one request of the client
+struct WsGroup {
+    id: jsonrpc::Id, // of the request they replaced
+    kind: WsGroupKind,
+    single: bool, // the client sent it alone
+    results: Vec<Option<JsonrpcResponseRawResult>>,
+}
+
+struct WsSubscription {
+    filter: Value,
+    upstream: Vec<String>,
+    seen: VecDeque<String>, // keys of logs delivered lately
+}
+
+// breaks log queries and subscriptions relayed over one ws connection, and
+// puts their responses and notifications back together. subscriptions of
+// the parts are merged under an id of ours
+pub struct WsLogSplitter {
+    groups: BTreeMap<usize, WsGroup>,
+    next_group: usize,
+    pending: BTreeMap<String, (usize, usize)>, // upstream id -> (group, position)
+    subs: BTreeMap<String, WsSubscription>,    // by merged subscription id
+    upstream_subs: BTreeMap<String, String>, // upstream subscription id -> merged, empty if dropped
+    rng: Rng,
+}
+
+impl WsLogSplitter {
+    pub fn new() -> Self {
+        Self {
+            groups: BTreeMap::new(),
+            next_group: 0,
+            pending: BTreeMap::new(),
+            subs: BTreeMap::new(),
+            upstream_subs: BTreeMap::new(),
+            rng: Rng::from_entropy(),
+        }
+    }
+
+    // upstream requests replacing `req`, `None` if it goes as is
+    fn replace(&mut self, req: &JsonrpcRawRequest, single: bool) -> Option<Vec<JsonrpcRawRequest>> {
+        let (kind, calls) = match req.method.as_str() {
+            "eth_getLogs" => {
+                let filter = request_filter(req)?;
+                let calls = split_filter(&filter)?
+                    .into_iter()
+                    .map(|v| serde_json::json!([v]))
+                    .collect::<Vec<_>>();
+                (WsGroupKind::Logs(filter), calls)
+            }
+            "eth_subscribe" => {
+                let filter = request_filter(req)?;
+                let calls = split_filter(&filter)?
+                    .into_iter()
+                    .map(|v| serde_json::json!(["logs", v]))
+                    .collect::<Vec<_>>();
+                (WsGroupKind::Subscribe(filter), calls)
+            }
+            "eth_unsubscribe" => {
+                let id = utils::get_jsonrpc_param(req, 0)?;
+                let sub = self.subs.remove(id.as_str()?)?;
+                for v in &sub.upstream {
+                    self.upstream_subs.remove(v);
+                }
+                let calls = sub
+                    .upstream
+                    .into_iter()
+                    .map(|v| serde_json::json!([v]))
+                    .collect::<Vec<_>>();
+                (WsGroupKind::Unsubscribe, calls)
+            }
+            _ => return None,
+        };
+
+        let group = self.next_group;
+        self.next_group += 1;
+        let mut reqs = vec![];
+        for params in calls {
+            // drawn on its own so the split queries can't be linked, within
+            // the range a javascript number holds exactly
+            let id = rng::entropy_u64() >> 11;
+            match JsonrpcRawRequest::new(id, &req.method, &params) {
+                Ok(v) => {
+                    if let Ok(key) = serde_json::to_string(&v.id) {
+                        self.pending.insert(key, (group, reqs.len()));
+                    }
+                    reqs.push(v);
+                }
+                Err(e) => glog::error!("build split request[{}] fail: {:?}", req.method, e),
+            }
+        }
+        let group_state = WsGroup {
+            id: req.id.clone(),
+            kind,
+            single,
+            results: (0..reqs.len()).map(|_| None).collect(),
+        };
+        self.groups.insert(group, group_state);
+        Some(reqs)
+    }
+
+    // break down log queries and subscriptions of `req_body` in place
+    pub fn split(&mut self, req_body: &mut Batchable<JsonrpcRawRequest>) {
+        let (reqs, single) = match req_body {
+            Batchable::Single(v) => (vec![v.clone()], true),
+            Batchable::Batch(vs) => (vs.clone(), false),
+        };
+        let mut out = vec![];
+        let mut split = false;
+        for req in reqs {
+            match self.replace(&req, single) {
+                Some(vs) => {
+                    out.extend(vs);
+                    split = true;
+                }
+                None => out.push(req),
+            }
+        }
+        if split {
+            *req_body = Batchable::Batch(out);
+        }
+    }
+
+    // response of the client request behind `group`, once all parts are back
+    fn finish(&mut self, group: usize) -> Option<JsonrpcRawResponseFull> {
+        if self.groups.get(&group)?.results.iter().any(|v| v.is_none()) {
+            return None;
+        }
+        let g = self.groups.remove(&group)?;
+        let id = Some(g.id);
+        let mut results = vec![];
+        let mut failed = false;
+        for r in g.results.into_iter().flatten() {
+            match r {
+                JsonrpcResponseRawResult::Ok(v) => results.push(v.result),
+                JsonrpcResponseRawResult::Err(_) => failed = true,
+            }
+        }
+        let result = match g.kind {
+            WsGroupKind::Logs(filter) => {
+                let lists = results
+                    .iter()
+                    .map(|v| serde_json::from_raw_value::<Vec<Value>>(v))
+                    .collect::<Result<Vec<_>, _>>();
+                match lists {
+                    Ok(lists) if !failed => {
+                        serde_json::to_raw_value(&merge_logs(&filter, lists)).ok()
+                    }
+                    _ => None,
+                }
+            }
+            WsGroupKind::Subscribe(filter) => {
+                let upstream = results
+                    .iter()
+                    .filter_map(|v| serde_json::from_raw_value::<String>(v).ok())
+                    .collect::<Vec<_>>();
+                if failed || upstream.len() != results.len() {
+                    // notifications of the parts that made it go nowhere
+                    for v in upstream {
+                        self.upstream_subs.insert(v, String::new());
+                    }
+                    None
+                } else {
+                    let merged =
+                        format!("0x{:016x}{:016x}", self.rng.next_u64(), self.rng.next_u64());
+                    for v in &upstream {
+                        self.upstream_subs.insert(v.clone(), merged.clone());
+                    }
+                    let sub = WsSubscription {
+                        filter,
+                        upstream,
+                        seen: VecDeque::new(),
+                    };
+                    self.subs.insert(merged.clone(), sub);
+                    serde_json::to_raw_value(&merged).ok()
+                }
+            }
+            WsGroupKind::Unsubscribe => serde_json::to_raw_value(&true).ok(),
+        };
+        match result {
+            Some(result) => Some(JsonrpcRawResponseFull {
+                jsonrpc: "2.0".into(),
+                result: Some(result),
+                error: None,
+                id,
+            }),
+            None => {
+                glog::error!("protect_logs_error: remote_response failed");
+                Some(JsonrpcRawResponseFull::err(sub_request_error(), id))
+            }
+        }
+    }
+
+    // a notification of a merged subscription, `None` if it's to be dropped
+    fn restore_notification(&mut self, mut msg: Value) -> Option<Vec<u8>> {
+        let upstream = msg.pointer("/params/subscription")?.as_str()?;
+        let merged = self.upstream_subs.get(upstream)?.clone();
+        let sub = self.subs.get_mut(&merged)?;
+        let log = msg.pointer("/params/result")?;
+        let empty = Map::new();
+        if !log_matches(sub.filter.as_object().unwrap_or(&empty), log) {
+            return None;
+        }
+        let key = log_key(log);
+        if sub.seen.contains(&key) {
+            return None;
+        }
+        sub.seen.push_back(key);
+        if sub.seen.len() > SEEN_LOGS {
+            sub.seen.pop_front();
+        }
+        *msg.pointer_mut("/params/subscription")? = Value::String(merged);
+        serde_json::to_vec(&msg).ok()
+    }
+
+    // put a frame from the upstream back together, `None` if nothing of it
+    // goes to the client
+    pub fn restore(&mut self, data: Vec<u8>) -> Option<Vec<u8>> {
+        if let Ok(msg) = serde_json::from_slice::<Value>(&data) {
+            if msg.get("method").and_then(|v| v.as_str()) == Some("eth_subscription") {
+                let upstream = msg.pointer("/params/subscription").and_then(|v| v.as_str());
+                return match upstream.and_then(|v| self.upstream_subs.get(v)) {
+                    Some(_) => self.restore_notification(msg),
+                    None => Some(data),
+                };
+            }
+        }
+        if self.pending.is_empty() {
+            return Some(data);
+        }
+        let rs = match Batchable::<JsonrpcResponseRawResult>::parse(&data) {
+            Ok(Batchable::Single(r)) => vec![r],
+            Ok(Batchable::Batch(rs)) => rs,
+            Err(_) => return Some(data),
+        };
+
+        let mut out = vec![];
+        let mut touched = vec![];
+        for r in rs {
+            let slot = serde_json::to_string(&utils::get_response_id(&r))
+                .ok()
+                .and_then(|id| self.pending.remove(&id));
+            match slot {
+                Some((group, pos)) => {
+                    if let Some(g) = self.groups.get_mut(&group) {
+                        g.results[pos] = Some(r);
+                    }
+                    if !touched.contains(&group) {
+                        touched.push(group);
+                    }
+                }
+                None => out.push(r.to_full()),
+            }
+        }
+        if touched.is_empty() {
+            return Some(data);
+        }
+        let mut single = true;
+        for group in touched {
+            single &= self.groups.get(&group).map(|v| v.single).unwrap_or(false);
+            out.extend(self.finish(group));
+        }
+        let resp = match out.len() {
+            0 => return None,
+            1 if single => Batchable::Single(out.remove(0)),
+            _ => Batchable::Batch(out),
+        };
+        serde_json::to_vec(&resp).ok()
+    }
+}
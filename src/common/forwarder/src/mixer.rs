@@ -0,0 +1,171 @@
+use std::prelude::v1::*;
+
+use std::time::{Duration, Instant};
+
+use crate::rng::Rng;
+use crate::types::JsonrpcRequestMgr;
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// holds upstream parts for a random delay, and releases the due ones of all
+// clients in one order, so neither the timing nor the sending order of the
+// parts tells which belong together
+pub struct Mixer<C: Clock = SystemClock> {
+    clock: C,
+    rng: Rng,
+}
+
+impl<C: Clock> Mixer<C> {
+    pub fn new(clock: C, rng: Rng) -> Self {
+        Self { clock, rng }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    // uniform within the budget, in whole milliseconds
+    pub fn delay(&mut self, budget: Duration) -> Duration {
+        let ms = budget.as_millis() as u64;
+        if ms == 0 {
+            return Duration::from_millis(0);
+        }
+        Duration::from_millis(self.rng.below(ms + 1))
+    }
+
+    // (req_id, part) of the unsent parts that are due, earliest first
+    pub fn due(&self, reqs: &JsonrpcRequestMgr) -> Vec<(usize, usize)> {
+        let now = self.now();
+        let mut due = vec![];
+        for (req_id, req) in reqs.iter() {
+            for (idx, part) in req.parts.iter().enumerate() {
+                if part.sent_at.is_none() && part.release_at <= now {
+                    due.push((part.release_at, *req_id, idx));
+                }
+            }
+        }
+        due.sort();
+        due.into_iter()
+            .map(|(_, req_id, idx)| (req_id, idx))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use jsonrpc::{Batchable, JsonrpcRawRequest};
+
+    use crate::route::{JsonrpcRoute, RouteConfig, RouteOptions};
+    use crate::sanitizer::SanitizedRequest;
+    use crate::types::JsonrpcForwardRequest;
+
+    // moved on by hand, shared with the test
+    #[derive(Clone)]
+    struct FakeClock(Rc<Cell<Instant>>);
+
+    impl FakeClock {
+        fn advance(&self, ms: u64) {
+            self.0.set(self.0.get() + Duration::from_millis(ms));
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    fn request(conn_id: usize, mix_delay_ms: u64) -> JsonrpcForwardRequest {
+        let route = JsonrpcRoute::from_config(RouteConfig::Full {
+            upstreams: vec!["https://rpc-a.example".into()],
+            options: RouteOptions {
+                mix_delay_ms,
+                ..Default::default()
+            },
+        })
+        .unwrap();
+        let params: Vec<serde_json::Value> = vec![];
+        let req = JsonrpcRawRequest::new(1, "eth_chainId", &params).unwrap();
+        let sr = SanitizedRequest::new(Batchable::Single(req));
+        JsonrpcForwardRequest::new(conn_id, "/eth".into(), route, sr)
+    }
+
+    // the parts `due` hands out, marked sent like the forwarder does
+    fn release(mixer: &Mixer<FakeClock>, reqs: &mut JsonrpcRequestMgr) -> Vec<(usize, usize)> {
+        let due = mixer.due(reqs);
+        for (req_id, part) in &due {
+            reqs.get_mut(req_id).unwrap().parts[*part].sent_at = Some(mixer.now());
+        }
+        due
+    }
+
+    #[test]
+    fn parts_held_until_due_and_released_in_order() {
+        let clock = FakeClock(Rc::new(Cell::new(Instant::now())));
+        let mut mixer = Mixer::new(clock.clone(), Rng::new(7));
+        let mut reqs = JsonrpcRequestMgr::new();
+        let start = mixer.now();
+        // pushed as req 0, 1, 2, due after 30, 10 and 20 ms
+        for (conn_id, ms) in [(0, 30), (1, 10), (2, 20)] {
+            let mut req = request(conn_id, 0);
+            req.split(&mut mixer);
+            req.parts[0].release_at = start + Duration::from_millis(ms);
+            reqs.push(req);
+        }
+
+        assert!(release(&mixer, &mut reqs).is_empty());
+        clock.advance(9);
+        assert!(release(&mixer, &mut reqs).is_empty());
+        clock.advance(1);
+        assert_eq!(release(&mixer, &mut reqs), vec![(1, 0)]);
+        // sent ones aren't handed out again
+        assert!(release(&mixer, &mut reqs).is_empty());
+        // both due by now, the earlier first
+        clock.advance(25);
+        assert_eq!(release(&mixer, &mut reqs), vec![(2, 0), (0, 0)]);
+        assert!(release(&mixer, &mut reqs).is_empty());
+    }
+
+    #[test]
+    fn split_holds_parts_within_the_budget() {
+        let clock = FakeClock(Rc::new(Cell::new(Instant::now())));
+        let mut mixer = Mixer::new(clock.clone(), Rng::new(7));
+        let mut reqs = JsonrpcRequestMgr::new();
+        let start = mixer.now();
+        for conn_id in 0..16 {
+            let mut req = request(conn_id, 100);
+            req.split(&mut mixer);
+            let release_at = req.parts[0].release_at;
+            assert!(release_at >= start);
+            assert!(release_at <= start + Duration::from_millis(100));
+            reqs.push(req);
+        }
+
+        let mut released = vec![];
+        for _ in 0..=100 {
+            for (req_id, part) in release(&mixer, &mut reqs) {
+                let release_at = reqs[&req_id].parts[part].release_at;
+                assert!(release_at <= mixer.now());
+                released.push(release_at);
+            }
+            clock.advance(1);
+        }
+        assert_eq!(released.len(), 16);
+        assert!(released.windows(2).all(|v| v[0] <= v[1]));
+    }
+}
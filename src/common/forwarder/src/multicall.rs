@@ -0,0 +1,178 @@
+use std::prelude::v1::*;
+
+use eth_types::{H160, U256};
+
+use crate::sol::{self, SolError, SolType, SolValue};
+
+// https://github.com/mds1/multicall/blob/main/src/Multicall3.sol
+pub const AGGREGATE_SIG: [u8; 4] = [0x25, 0x2d, 0xba, 0x42]; // aggregate((address,bytes)[])
+pub const TRY_AGGREGATE_SIG: [u8; 4] = [0xbc, 0xe3, 0x8b, 0xd7]; // tryAggregate(bool,(address,bytes)[])
+pub const AGGREGATE3_SIG: [u8; 4] = [0x82, 0xad, 0x56, 0xcb]; // aggregate3((address,bool,bytes)[])
+pub const AGGREGATE3_VALUE_SIG: [u8; 4] = [0x17, 0x4d, 0xea, 0x71]; // aggregate3Value((address,bool,uint256,bytes)[])
+
+// read-only sub-calls we know how to split, and which argument is the owner
+const BALANCE_OF_SIG: [u8; 4] = [0x70, 0xa0, 0x82, 0x31]; // balanceOf(address)
+const ALLOWANCE_SIG: [u8; 4] = [0xdd, 0x62, 0xed, 0x3e]; // allowance(address,address)
+const OWNER_OF_SIG: [u8; 4] = [0x63, 0x52, 0x21, 0x1e]; // ownerOf(uint256)
+const GET_ETH_BALANCE_SIG: [u8; 4] = [0x4d, 0x23, 0x01, 0xcc]; // getEthBalance(address)
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AggregateKind {
+    Aggregate,
+    TryAggregate { require_success: bool },
+    Aggregate3,
+    Aggregate3Value,
+}
+
+impl AggregateKind {
+    pub fn sig(&self) -> &'static str {
+        match self {
+            AggregateKind::Aggregate => "0x252dba42",
+            AggregateKind::TryAggregate { .. } => "0xbce38bd7",
+            AggregateKind::Aggregate3 => "0x82ad56cb",
+            AggregateKind::Aggregate3Value => "0x174dea71",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Call {
+    pub target: H160,
+    pub allow_failure: bool,
+    pub value: U256,
+    pub data: Vec<u8>,
+}
+
+impl Call {
+    // `None` if the sub-call is not a known read-only one,
+    // `Some(None)` if it is but carries no owner (e.g. `ownerOf`)
+    pub fn owner(&self) -> Option<Option<H160>> {
+        let (sig, args) = sol::split_selector(&self.data).ok()?;
+        if sig == BALANCE_OF_SIG || sig == GET_ETH_BALANCE_SIG {
+            let vs = sol::decode(&[SolType::Address], args).ok()?;
+            vs[0].as_address().cloned().map(Some)
+        } else if sig == ALLOWANCE_SIG {
+            let vs = sol::decode(&[SolType::Address, SolType::Address], args).ok()?;
+            vs[0].as_address().cloned().map(Some)
+        } else if sig == OWNER_OF_SIG {
+            sol::decode(&[SolType::Uint(256)], args).ok().map(|_| None)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Aggregate {
+    pub kind: AggregateKind,
+    pub calls: Vec<Call>,
+}
+
+impl Aggregate {
+    pub fn decode(calldata: &[u8]) -> Result<Option<Self>, SolError> {
+        let (sig, args) = sol::split_selector(calldata)?;
+        let call = |members: Vec<SolType>| SolType::Array(Box::new(SolType::Tuple(members)));
+        let (kind, tys) = if sig == AGGREGATE_SIG {
+            let tys = vec![call(vec![SolType::Address, SolType::Bytes])];
+            (AggregateKind::Aggregate, tys)
+        } else if sig == TRY_AGGREGATE_SIG {
+            let tys = vec![SolType::Bool, call(vec![SolType::Address, SolType::Bytes])];
+            (
+                AggregateKind::TryAggregate {
+                    require_success: false,
+                },
+                tys,
+            )
+        } else if sig == AGGREGATE3_SIG {
+            let tys = vec![call(vec![SolType::Address, SolType::Bool, SolType::Bytes])];
+            (AggregateKind::Aggregate3, tys)
+        } else if sig == AGGREGATE3_VALUE_SIG {
+            let tys = vec![call(vec![
+                SolType::Address,
+                SolType::Bool,
+                SolType::Uint(256),
+                SolType::Bytes,
+            ])];
+            (AggregateKind::Aggregate3Value, tys)
+        } else {
+            return Ok(None);
+        };
+
+        let mut vs = sol::decode(&tys, args)?;
+        let kind = match kind {
+            AggregateKind::TryAggregate { .. } => AggregateKind::TryAggregate {
+                require_success: vs[0].as_bool().unwrap_or(true),
+            },
+            kind => kind,
+        };
+        let list = match vs.pop() {
+            Some(SolValue::Array(list)) => list,
+            _ => return Err(SolError::InvalidValue("calls")),
+        };
+
+        let mut calls = Vec::with_capacity(list.len());
+        for v in list {
+            let members = v.as_list().ok_or(SolError::InvalidValue("call"))?;
+            let target = members[0].as_address().cloned();
+            let (allow_failure, value, data) = match kind {
+                AggregateKind::Aggregate | AggregateKind::TryAggregate { .. } => {
+                    (Some(false), Some(U256::zero()), members[1].as_bytes())
+                }
+                AggregateKind::Aggregate3 => (
+                    members[1].as_bool(),
+                    Some(U256::zero()),
+                    members[2].as_bytes(),
+                ),
+                AggregateKind::Aggregate3Value => (
+                    members[1].as_bool(),
+                    members[2].as_uint().cloned(),
+                    members[3].as_bytes(),
+                ),
+            };
+            match (target, allow_failure, value, data) {
+                (Some(target), Some(allow_failure), Some(value), Some(data)) => calls.push(Call {
+                    target,
+                    allow_failure,
+                    value,
+                    data: data.to_vec(),
+                }),
+                _ => return Err(SolError::InvalidValue("call")),
+            }
+        }
+        Ok(Some(Self { kind, calls }))
+    }
+
+    // whether a failed sub-call makes the whole aggregate revert
+    pub fn must_succeed(&self, call: &Call) -> bool {
+        match self.kind {
+            AggregateKind::Aggregate => true,
+            AggregateKind::TryAggregate { require_success } => require_success,
+            AggregateKind::Aggregate3 | AggregateKind::Aggregate3Value => !call.allow_failure,
+        }
+    }
+
+    // `aggregate` returns (uint256 blockNumber, bytes[] returnData),
+    // the others return (bool success, bytes returnData)[]
+    pub fn encode_result(&self, block_number: U256, results: &[(bool, Vec<u8>)]) -> Vec<u8> {
+        match self.kind {
+            AggregateKind::Aggregate => {
+                let items = results
+                    .iter()
+                    .map(|(_, data)| SolValue::Bytes(data.clone()))
+                    .collect();
+                sol::encode(&[SolValue::Uint(block_number), SolValue::Array(items)])
+            }
+            _ => {
+                let items = results
+                    .iter()
+                    .map(|(success, data)| {
+                        SolValue::Tuple(vec![
+                            SolValue::Bool(*success),
+                            SolValue::Bytes(data.clone()),
+                        ])
+                    })
+                    .collect();
+                sol::encode(&[SolValue::Array(items)])
+            }
+        }
+    }
+}
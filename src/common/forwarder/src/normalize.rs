@@ -0,0 +1,245 @@
+use std::prelude::v1::*;
+
+use serde_json::{Map, Value};
+
+// wallets serialize the same request in their own ways (checksum addresses,
+// zero padded quantities, extra txn fields..), which is enough to tell them
+// apart. here params of common methods are put into one form, keys are
+// ordered by `Map` itself
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Address,
+    Quantity,
+    Data,
+    Block,
+    Txn,
+    Filter,
+    Any,
+}
+
+fn param_kinds(method: &str) -> Option<&'static [Kind]> {
+    Some(match method {
+        "eth_getBalance" | "eth_getTransactionCount" | "eth_getCode" => {
+            &[Kind::Address, Kind::Block]
+        }
+        "eth_getStorageAt" => &[Kind::Address, Kind::Quantity, Kind::Block],
+        "eth_call" | "eth_estimateGas" => &[Kind::Txn, Kind::Block],
+        "eth_getBlockByNumber" => &[Kind::Block, Kind::Any],
+        "eth_getBlockByHash" => &[Kind::Data, Kind::Any],
+        "eth_getTransactionByHash"
+        | "eth_getTransactionReceipt"
+        | "eth_getBlockTransactionCountByHash"
+        | "eth_sendRawTransaction" => &[Kind::Data],
+        "eth_getLogs" => &[Kind::Filter],
+        _ => return None,
+    })
+}
+
+// calls whose result doesn't depend on the caller
+const CALLER_FREE_SIGS: [&str; 14] = [
+    "0x70a08231", // balanceOf(address)
+    "0xdd62ed3e", // allowance(address,address)
+    "0x18160ddd", // totalSupply()
+    "0x313ce567", // decimals()
+    "0x95d89b41", // symbol()
+    "0x06fdde03", // name()
+    "0x6352211e", // ownerOf(uint256)
+    "0x4d2301cc", // getEthBalance(address)
+    "0xf0002ea9", // balances(address[],address[])
+    "0x252dba42", // aggregate((address,bytes)[]), sub-calls see the multicall as sender
+    "0xbce38bd7", // tryAggregate(bool,(address,bytes)[])
+    "0x82ad56cb", // aggregate3((address,bool,bytes)[])
+    "0x0f28c97d", // getCurrentBlockTimestamp()
+    "0x42cbb15c", // getBlockNumber()
+];
+
+// fields of `eth_call` that don't change a read
+const CALL_GAS_FIELDS: [&str; 4] = ["gas", "gasPrice", "maxFeePerGas", "maxPriorityFeePerGas"];
+
+struct Normalizer {
+    rules: Vec<&'static str>,
+}
+
+impl Normalizer {
+    fn applied(&mut self, rule: &'static str) {
+        if !self.rules.contains(&rule) {
+            self.rules.push(rule);
+        }
+    }
+
+    fn value(&mut self, kind: Kind, v: &mut Value) {
+        match kind {
+            Kind::Address => self.address(v),
+            Kind::Quantity => self.quantity(v),
+            Kind::Data => self.data(v),
+            Kind::Block => self.block(v),
+            Kind::Txn => {
+                if let Value::Object(txn) = v {
+                    self.txn(txn)
+                }
+            }
+            Kind::Filter => {
+                if let Value::Object(filter) = v {
+                    self.filter(filter)
+                }
+            }
+            Kind::Any => {}
+        }
+    }
+
+    fn address(&mut self, v: &mut Value) {
+        if let Value::String(s) = v {
+            if is_hex(s) && s.len() == 42 && s.chars().any(|c| c.is_ascii_uppercase()) {
+                *s = s.to_ascii_lowercase();
+                self.applied("lowercase_address");
+            }
+        }
+    }
+
+    fn data(&mut self, v: &mut Value) {
+        if let Value::String(s) = v {
+            if is_hex(s) && s.chars().any(|c| c.is_ascii_uppercase()) {
+                *s = s.to_ascii_lowercase();
+                self.applied("lowercase_data");
+            }
+        }
+    }
+
+    fn quantity(&mut self, v: &mut Value) {
+        if let Value::String(s) = v {
+            if !is_hex(s) {
+                return;
+            }
+            let digits = s[2..].trim_start_matches('0').to_ascii_lowercase();
+            let trimmed = if digits.is_empty() {
+                "0x0".to_owned()
+            } else {
+                format!("0x{}", digits)
+            };
+            if *s != trimmed {
+                *s = trimmed;
+                self.applied("trim_quantity");
+            }
+        }
+    }
+
+    fn block(&mut self, v: &mut Value) {
+        match v {
+            Value::String(s) if !s.starts_with("0x") => {
+                let tag = s.to_ascii_lowercase();
+                if *s != tag {
+                    *s = tag;
+                    self.applied("lowercase_tag");
+                }
+            }
+            Value::String(_) => self.quantity(v),
+            Value::Object(obj) => {
+                if let Some(n) = obj.get_mut("blockNumber") {
+                    self.block(n);
+                }
+                if let Some(hash) = obj.get_mut("blockHash") {
+                    self.data(hash);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn txn(&mut self, txn: &mut Map<String, Value>) {
+        if !txn.contains_key("data") {
+            if let Some(input) = txn.remove("input") {
+                txn.insert("data".into(), input);
+                self.applied("input_as_data");
+            }
+        }
+        for (k, v) in txn.iter_mut() {
+            match k.as_str() {
+                "from" | "to" => self.address(v),
+                "gas"
+                | "gasPrice"
+                | "maxFeePerGas"
+                | "maxPriorityFeePerGas"
+                | "value"
+                | "nonce" => self.quantity(v),
+                "data" | "input" => self.data(v),
+                _ => {}
+            }
+        }
+    }
+
+    fn filter(&mut self, filter: &mut Map<String, Value>) {
+        for (k, v) in filter.iter_mut() {
+            match k.as_str() {
+                "address" => match v {
+                    Value::Array(list) => list.iter_mut().for_each(|v| self.address(v)),
+                    v => self.address(v),
+                },
+                "fromBlock" | "toBlock" => self.block(v),
+                "blockHash" => self.data(v),
+                "topics" => {
+                    if let Value::Array(topics) = v {
+                        for topic in topics {
+                            match topic {
+                                Value::Array(list) => list.iter_mut().for_each(|v| self.data(v)),
+                                v => self.data(v),
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // `eth_call` only reads, gas settings don't matter
+    fn drop_gas(&mut self, txn: &mut Map<String, Value>) {
+        for k in CALL_GAS_FIELDS {
+            if txn.remove(k).is_some() {
+                self.applied("drop_gas_fields");
+            }
+        }
+    }
+
+    // `from` ties the call to the wallet, drop it when the callee can't tell
+    fn strip_from(&mut self, txn: &mut Map<String, Value>) {
+        let zero_value = match txn.get("value").and_then(|v| v.as_str()) {
+            Some(v) => v == "0x0",
+            None => !txn.contains_key("value"),
+        };
+        let caller_free = txn
+            .get("data")
+            .and_then(|v| v.as_str())
+            .and_then(|v| v.get(..10))
+            .map(|sig| CALLER_FREE_SIGS.contains(&sig))
+            .unwrap_or(false);
+        if zero_value && caller_free && txn.remove("from").is_some() {
+            self.applied("strip_from");
+        }
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    s.len() > 2 && s.starts_with("0x") && s[2..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// rules applied to `params`, empty if nothing changed
+pub fn normalize(method: &str, params: &mut Vec<Value>, strip_from: bool) -> Vec<&'static str> {
+    let kinds = match param_kinds(method) {
+        Some(v) => v,
+        None => return vec![],
+    };
+    let mut n = Normalizer { rules: vec![] };
+    for (kind, v) in kinds.iter().zip(params.iter_mut()) {
+        n.value(*kind, v);
+    }
+    if let Some(Value::Object(txn)) = params.get_mut(0) {
+        if method == "eth_call" {
+            n.drop_gas(txn);
+        }
+        if strip_from && (method == "eth_call" || method == "eth_estimateGas") {
+            n.strip_from(txn);
+        }
+    }
+    n.rules
+}
@@ -0,0 +1,338 @@
+use std::prelude::v1::*;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// headers telling the client address, tried in order
+pub const DEFAULT_CLIENT_IP_HEADERS: [&str; 3] =
+    ["cf-connecting-ip", "forwarded", "x-forwarded-for"];
+
+// "10.0.0.0/8", "fd00::/8", or a single address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let fail = || format!("invalid cidr {}", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = canonical(addr.trim().parse::<IpAddr>().map_err(|_| fail())?);
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(v) => v.trim().parse::<u8>().map_err(|_| fail())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(fail());
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, canonical(*ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// an ipv4 peer accepted on an ipv6 socket as the ipv4 address
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v) => match v.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                IpAddr::V4(Ipv4Addr::from(((hi as u32) << 16) | lo as u32))
+            }
+            _ => IpAddr::V6(v),
+        },
+        v => v,
+    }
+}
+
+// peers allowed to tell the client address by headers, e.g. a load
+// balancer in front of us. nobody by default, the peer is the client then
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    cidrs: Vec<Cidr>,
+    headers: Vec<String>, // lowercase
+}
+
+impl TrustedProxies {
+    pub fn new<S: AsRef<str>>(cidrs: &[S], headers: &[S]) -> Result<Self, String> {
+        let cidrs = cidrs
+            .iter()
+            .map(|v| Cidr::parse(v.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let headers = headers
+            .iter()
+            .map(|v| v.as_ref().trim().to_ascii_lowercase())
+            .filter(|v| !v.is_empty())
+            .collect();
+        Ok(Self { cidrs, headers })
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.cidrs.iter().any(|v| v.contains(ip))
+    }
+
+    // the client behind `peer`. `header` gives every value of a header in
+    // the order received. hops are walked from the nearest one, the first
+    // not being a trusted proxy is the client
+    pub fn client_ip<F>(&self, peer: Option<SocketAddr>, mut header: F) -> Option<IpAddr>
+    where
+        F: FnMut(&str) -> Vec<String>,
+    {
+        let peer = canonical(peer?.ip());
+        if !self.is_trusted(&peer) {
+            return Some(peer);
+        }
+        for name in &self.headers {
+            let values = header(name);
+            if values.is_empty() {
+                continue;
+            }
+            let hops = match name.as_str() {
+                "forwarded" => forwarded_for(&values),
+                _ => values
+                    .iter()
+                    .flat_map(|v| v.split(','))
+                    .map(|v| v.trim().to_owned())
+                    .collect(),
+            };
+            let mut client = None;
+            for hop in hops.iter().rev() {
+                match parse_node(hop) {
+                    Some(ip) if self.is_trusted(&ip) => client = Some(ip),
+                    Some(ip) => return Some(ip),
+                    // obfuscated or garbage, nothing left of it can be told
+                    None => {
+                        glog::warn!("unreadable {} hop: {}", name, hop);
+                        client = None;
+                        break;
+                    }
+                }
+            }
+            if client.is_some() {
+                return client;
+            }
+        }
+        Some(peer)
+    }
+}
+
+// `for` of each element of RFC 7239 `Forwarded` headers, in order
+fn forwarded_for(values: &[String]) -> Vec<String> {
+    let mut hops = vec![];
+    for value in values {
+        for elem in split_quoted(value, ',') {
+            for pair in split_quoted(&elem, ';') {
+                if let Some((k, v)) = pair.split_once('=') {
+                    if k.trim().eq_ignore_ascii_case("for") {
+                        hops.push(v.trim().to_owned());
+                    }
+                }
+            }
+        }
+    }
+    hops
+}
+
+// split by `sep` outside of quoted strings
+fn split_quoted(s: &str, sep: char) -> Vec<String> {
+    let mut out = vec![];
+    let mut cur = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in s.chars() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            out.push(std::mem::take(&mut cur));
+            continue;
+        }
+        cur.push(c);
+    }
+    out.push(cur);
+    out
+}
+
+// "1.2.3.4", "1.2.3.4:80", "[::1]", "[::1]:80", or quoted. `None` for
+// "unknown" and obfuscated identifiers
+fn parse_node(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    if let Some(v) = s.strip_prefix('[') {
+        let (v6, _) = v.split_once(']')?;
+        return v6
+            .parse::<Ipv6Addr>()
+            .ok()
+            .map(|v| canonical(IpAddr::V6(v)));
+    }
+    if let Ok(v) = s.parse::<IpAddr>() {
+        return Some(canonical(v));
+    }
+    // ipv4 with port
+    let (v4, port) = s.rsplit_once(':')?;
+    port.parse::<u16>().ok()?;
+    v4.parse::<Ipv4Addr>().ok().map(IpAddr::V4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn peer(s: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip(s), 443))
+    }
+
+    // the client told by `proxies` for a request from `from` with `headers`
+    fn client(proxies: &TrustedProxies, from: &str, headers: &[(&str, &str)]) -> IpAddr {
+        proxies
+            .client_ip(peer(from), |name| {
+                headers
+                    .iter()
+                    .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+                    .map(|(_, v)| v.to_string())
+                    .collect()
+            })
+            .unwrap()
+    }
+
+    fn proxies(cidrs: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(cidrs, &DEFAULT_CLIENT_IP_HEADERS).unwrap()
+    }
+
+    #[test]
+    fn cidrs() {
+        let net = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(net.contains(&ip("10.255.0.1")));
+        assert!(!net.contains(&ip("11.0.0.1")));
+        assert!(!net.contains(&ip("fd00::1")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")));
+        assert!(Cidr::parse("fd00::/8").unwrap().contains(&ip("fd12::1")));
+        assert!(Cidr::parse("192.0.2.1").unwrap().contains(&ip("192.0.2.1")));
+        assert!(!Cidr::parse("192.0.2.1").unwrap().contains(&ip("192.0.2.2")));
+
+        assert!(Cidr::parse("10.0.0.0/32").is_ok());
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("fd00::/128").is_ok());
+        assert!(Cidr::parse("fd00::/129").is_err());
+        assert!(Cidr::parse("10.0.0.0/x").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+    }
+
+    #[test]
+    fn mapped_ipv4() {
+        // both the cidr and the peer may be written either way
+        assert_eq!(Cidr::parse("::ffff:10.0.0.0/8"), Cidr::parse("10.0.0.0/8"));
+        assert!(Cidr::parse("10.0.0.0/8")
+            .unwrap()
+            .contains(&ip("::ffff:10.1.2.3")));
+
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = [("x-forwarded-for", "203.0.113.7")];
+        assert_eq!(
+            client(&proxies, "::ffff:10.1.2.3", &headers),
+            ip("203.0.113.7")
+        );
+        // the client itself is told as ipv4
+        assert_eq!(
+            client(&proxies, "::ffff:203.0.113.9", &headers),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let headers = [
+            ("cf-connecting-ip", "203.0.113.7"),
+            ("x-forwarded-for", "203.0.113.8"),
+        ];
+        let nobody = TrustedProxies::default();
+        assert_eq!(
+            client(&nobody, "198.51.100.1", &headers),
+            ip("198.51.100.1")
+        );
+        let proxies = proxies(&["10.0.0.0/8"]);
+        assert_eq!(
+            client(&proxies, "198.51.100.1", &headers),
+            ip("198.51.100.1")
+        );
+        assert_eq!(client(&proxies, "10.0.0.2", &headers), ip("203.0.113.7"));
+        assert_eq!(proxies.client_ip(None, |_| vec![]), None);
+    }
+
+    #[test]
+    fn rightmost_untrusted_hop() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        // the leftmost ones are made up by the client
+        let headers = [("x-forwarded-for", "1.1.1.1, 203.0.113.7, 10.0.0.3")];
+        assert_eq!(client(&proxies, "10.0.0.2", &headers), ip("203.0.113.7"));
+        // hops over several headers, in the order received
+        let headers = [
+            ("x-forwarded-for", "1.1.1.1"),
+            ("x-forwarded-for", "203.0.113.7,10.0.0.3"),
+        ];
+        assert_eq!(client(&proxies, "10.0.0.2", &headers), ip("203.0.113.7"));
+        // only proxies, the farthest one is the client
+        let headers = [("x-forwarded-for", "10.0.0.4, 10.0.0.3")];
+        assert_eq!(client(&proxies, "10.0.0.2", &headers), ip("10.0.0.4"));
+        // garbage ends the walk, the peer is left
+        let headers = [("x-forwarded-for", "1.1.1.1, bogus, 10.0.0.3")];
+        assert_eq!(client(&proxies, "10.0.0.2", &headers), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn forwarded_header() {
+        let value = r#"for="[2001:db8::1]:4711";proto=https, for=192.0.2.60:80;by="a,b""#;
+        assert_eq!(
+            forwarded_for(&[value.to_owned()]),
+            vec![r#""[2001:db8::1]:4711""#, "192.0.2.60:80"]
+        );
+        assert_eq!(
+            split_quoted(r#"a="x,\"y";b,c"#, ','),
+            vec![r#"a="x,\"y";b"#, "c"]
+        );
+
+        assert_eq!(
+            parse_node(r#""[2001:db8::1]:4711""#),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("192.0.2.60:80"), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node("[::ffff:192.0.2.60]:80"), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = [(
+            "Forwarded",
+            r#"for=198.51.100.1, for="[2001:db8::1]:4711";proto=https, for=10.0.0.3"#,
+        )];
+        assert_eq!(client(&proxies, "10.0.0.2", &headers), ip("2001:db8::1"));
+        // an unknown hop can't be seen past
+        let headers = [("forwarded", "for=198.51.100.1, for=unknown")];
+        assert_eq!(client(&proxies, "10.0.0.2", &headers), ip("10.0.0.2"));
+    }
+}
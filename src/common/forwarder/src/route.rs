@@ -0,0 +1,589 @@
+use std::prelude::v1::*;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use eth_types::H160;
+use net_http::Uri;
+use serde::{Deserialize, Serialize};
+
+use crate::abi_rule::{AbiRule, AbiRuleConfig};
+use crate::rng::Rng;
+use crate::sanitizer::DEFAULT_RULES;
+use crate::utils;
+
+// how the upstream requests of one decomposed client request are dispatched
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum SplitPolicy {
+    // all in one batch over one connection
+    #[serde(rename = "batch")]
+    Batch,
+    // one http request per sub-request, same upstream
+    #[serde(rename = "separate")]
+    Separate,
+    // one http request per sub-request, rotating through the route's upstreams
+    #[serde(rename = "spread")]
+    Spread,
+}
+
+impl Default for SplitPolicy {
+    fn default() -> Self {
+        SplitPolicy::Spread
+    }
+}
+
+// well-known deployments, the same address on most chains
+// https://github.com/mds1/multicall
+pub const MULTICALL3_ADDRESS: &str = "0xcA11bde05977b3631167028862bE2a173976CA11";
+// https://github.com/wbobeirne/eth-balance-checker
+pub const BALANCE_CHECKER_ADDRESS: &str = "0xb1f8e55c7f64d203c1400b9d8555d050f94adf39";
+
+// contracts whose calls may be broken down, calls of the same selector to
+// any other address are forwarded as is
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ContractRegistry {
+    pub balance_checkers: Vec<String>,
+    pub multicalls: Vec<String>,
+}
+
+impl Default for ContractRegistry {
+    fn default() -> Self {
+        Self {
+            balance_checkers: vec![BALANCE_CHECKER_ADDRESS.into()],
+            multicalls: vec![MULTICALL3_ADDRESS.into()],
+        }
+    }
+}
+
+impl ContractRegistry {
+    pub fn balance_checkers(&self) -> Vec<H160> {
+        parse_addresses(&self.balance_checkers)
+    }
+
+    pub fn multicalls(&self) -> Vec<H160> {
+        parse_addresses(&self.multicalls)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for v in self.balance_checkers.iter().chain(&self.multicalls) {
+            if utils::parse_address(v).is_none() {
+                return Err(format!("invalid contract address {}", v));
+            }
+        }
+        Ok(())
+    }
+}
+
+// User-Agent of upstream requests if the route sets none
+pub const UPSTREAM_UA: &str = "1rpc-demo/0.1";
+
+// picked from by an empty `user_agent` pool
+pub const COMMON_USER_AGENTS: [&str; 5] = [
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.1 Safari/605.1.15",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:121.0) Gecko/20100101 Firefox/121.0",
+    "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+];
+
+// headers of every upstream request, set by us or by the http client
+pub const BASE_HEADERS: [&str; 4] = ["Host", "Content-Type", "Content-Length", "Connection"];
+
+// one fixed string, or one of a pool picked per request
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum UserAgentPolicy {
+    Fixed(String),
+    Pool(Vec<String>), // `COMMON_USER_AGENTS` if empty
+}
+
+// what upstream requests carry besides `BASE_HEADERS`. no header of the
+// client is passed on
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct HeaderPolicy {
+    // `UPSTREAM_UA` if not set
+    pub user_agent: Option<UserAgentPolicy>,
+    pub accept_encoding: Option<String>,
+    pub accept_language: Option<String>,
+    // sent as is, e.g. auth of the upstream
+    pub extra: BTreeMap<String, String>,
+}
+
+impl HeaderPolicy {
+    pub fn user_agent(&self, rng: &mut Rng) -> String {
+        let pool = match &self.user_agent {
+            None => return UPSTREAM_UA.into(),
+            Some(UserAgentPolicy::Fixed(v)) => return v.clone(),
+            Some(UserAgentPolicy::Pool(vs)) if vs.is_empty() => COMMON_USER_AGENTS.to_vec(),
+            Some(UserAgentPolicy::Pool(vs)) => vs.iter().map(|v| v.as_str()).collect(),
+        };
+        pool[rng.below(pool.len() as u64) as usize].to_owned()
+    }
+
+    // headers other than User-Agent, the same for every request
+    pub fn fixed(&self) -> Vec<(String, String)> {
+        let mut headers = vec![];
+        if let Some(v) = &self.accept_encoding {
+            headers.push(("Accept-Encoding".to_owned(), v.clone()));
+        }
+        if let Some(v) = &self.accept_language {
+            headers.push(("Accept-Language".to_owned(), v.clone()));
+        }
+        headers.extend(self.extra.iter().map(|(k, v)| (k.clone(), v.clone())));
+        headers
+    }
+
+    // names of all headers sent upstream
+    pub fn sent(&self) -> Vec<String> {
+        let mut names = BASE_HEADERS
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>();
+        names.push("User-Agent".into());
+        names.extend(self.fixed().into_iter().map(|(k, _)| k));
+        names
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let owned = ["User-Agent", "Accept-Encoding", "Accept-Language"];
+        for (k, v) in &self.extra {
+            let valid = !k.is_empty()
+                && k.bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_');
+            if !valid {
+                return Err(format!("invalid header {}", k));
+            }
+            if BASE_HEADERS
+                .iter()
+                .chain(&owned)
+                .any(|v| v.eq_ignore_ascii_case(k))
+            {
+                return Err(format!("header {} is not configurable by extra", k));
+            }
+            if v.contains('\r') || v.contains('\n') {
+                return Err(format!("invalid value of header {}", k));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 5_000;
+pub const DEFAULT_READ_TIMEOUT_MS: u64 = 20_000;
+
+// how long an upstream request may take, e.g.
+//   "timeouts": { "connect_ms": 3000, "read_ms": 10000, "methods": { "eth_getLogs": 60000, "debug_trace*": 120000 } }
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TimeoutPolicy {
+    // a released request not written upstream by then, `DEFAULT_CONNECT_TIMEOUT_MS` if not set
+    pub connect_ms: Option<u64>,
+    // a written request not answered by then, `DEFAULT_READ_TIMEOUT_MS` if not set
+    pub read_ms: Option<u64>,
+    // read timeouts by method, a trailing `*` matches any method of the prefix
+    pub methods: BTreeMap<String, u64>,
+}
+
+impl TimeoutPolicy {
+    pub fn connect(&self) -> Duration {
+        Duration::from_millis(self.connect_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS))
+    }
+
+    // read timeout of an upstream request carrying `methods`, the longest
+    // of theirs
+    pub fn read<'a, I: IntoIterator<Item = &'a str>>(&self, methods: I) -> Duration {
+        let default = self.read_ms.unwrap_or(DEFAULT_READ_TIMEOUT_MS);
+        let ms = methods
+            .into_iter()
+            .map(|v| self.method(v).unwrap_or(default))
+            .max()
+            .unwrap_or(default);
+        Duration::from_millis(ms)
+    }
+
+    // exact match first, then the longest prefix
+    fn method(&self, method: &str) -> Option<u64> {
+        if let Some(v) = self.methods.get(method) {
+            return Some(*v);
+        }
+        self.methods
+            .iter()
+            .filter_map(|(k, v)| Some((k.strip_suffix('*')?, v)))
+            .filter(|(prefix, _)| method.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, v)| *v)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let zero = self.connect_ms == Some(0)
+            || self.read_ms == Some(0)
+            || self.methods.values().any(|v| *v == 0);
+        if zero {
+            return Err("zero timeout".into());
+        }
+        Ok(())
+    }
+}
+
+// which upstream a client request starts from
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum BalanceStrategy {
+    // each in turn
+    #[serde(rename = "round_robin")]
+    RoundRobin,
+    // at random, in proportion to `weights`
+    #[serde(rename = "weighted")]
+    Weighted,
+    // the fewest upstream requests in flight
+    #[serde(rename = "least_outstanding")]
+    LeastOutstanding,
+    // the lowest moving average latency, weighed by requests in flight
+    #[serde(rename = "ewma")]
+    Ewma,
+}
+
+impl Default for BalanceStrategy {
+    fn default() -> Self {
+        BalanceStrategy::RoundRobin
+    }
+}
+
+// e.g. "balance": { "strategy": "weighted", "weights": [3, 1] }
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BalancePolicy {
+    pub strategy: BalanceStrategy,
+    // by upstream index, 1 if not listed
+    pub weights: Vec<u64>,
+}
+
+impl BalancePolicy {
+    pub fn weight(&self, idx: usize) -> u64 {
+        self.weights.get(idx).cloned().unwrap_or(1)
+    }
+
+    fn validate(&self, upstreams: usize) -> Result<(), String> {
+        if self.weights.len() > upstreams {
+            return Err("more weights than upstreams".into());
+        }
+        if (0..upstreams).all(|idx| self.weight(idx) == 0) {
+            return Err("all weights are zero".into());
+        }
+        Ok(())
+    }
+}
+
+// how upstreams are told out of order, e.g.
+//   "health": { "method": "eth_blockNumber", "interval_ms": 10000, "probe_failures": 3, "live_errors": 5, "open_ms": 30000 }
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct HealthPolicy {
+    // called on each upstream in the background, e.g. "system_health"
+    pub method: String,
+    // between probes, 0 to disable
+    pub interval_ms: u64,
+    // failed probes in a row taking the upstream out of rotation
+    pub probe_failures: usize,
+    // failed upstream requests in a row taking the upstream out of rotation
+    pub live_errors: usize,
+    // out of rotation for this long, then a trial request is let through
+    pub open_ms: u64,
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        Self {
+            method: "eth_chainId".into(),
+            interval_ms: 30_000,
+            probe_failures: 3,
+            live_errors: 5,
+            open_ms: 30_000,
+        }
+    }
+}
+
+impl HealthPolicy {
+    fn validate(&self) -> Result<(), String> {
+        if self.method.is_empty() {
+            return Err("empty health method".into());
+        }
+        if self.probe_failures == 0 || self.live_errors == 0 {
+            return Err("zero health threshold".into());
+        }
+        Ok(())
+    }
+}
+
+pub const DEFAULT_SUB_RETRIES: usize = 2;
+pub const DEFAULT_FAILOVERS: usize = 2;
+
+// read methods answered alike by any upstream, safe to send again. anything
+// else, e.g. `eth_sendRawTransaction` or filter polls, is sent only once
+const IDEMPOTENT_METHODS: [&str; 33] = [
+    "eth_blockNumber",
+    "eth_call",
+    "eth_chainId",
+    "eth_estimateGas",
+    "eth_feeHistory",
+    "eth_gasPrice",
+    "eth_getBalance",
+    "eth_getBlockByHash",
+    "eth_getBlockByNumber",
+    "eth_getBlockReceipts",
+    "eth_getBlockTransactionCountByHash",
+    "eth_getBlockTransactionCountByNumber",
+    "eth_getCode",
+    "eth_getLogs",
+    "eth_getProof",
+    "eth_getStorageAt",
+    "eth_getTransactionByBlockHashAndIndex",
+    "eth_getTransactionByBlockNumberAndIndex",
+    "eth_getTransactionByHash",
+    "eth_getTransactionCount",
+    "eth_getTransactionReceipt",
+    "eth_getUncleByBlockHashAndIndex",
+    "eth_getUncleByBlockNumberAndIndex",
+    "eth_getUncleCountByBlockHash",
+    "eth_getUncleCountByBlockNumber",
+    "eth_maxPriorityFeePerGas",
+    "eth_protocolVersion",
+    "eth_syncing",
+    "net_listening",
+    "net_peerCount",
+    "net_version",
+    "web3_clientVersion",
+    "web3_sha3",
+];
+const IDEMPOTENT_PREFIXES: [&str; 2] = ["debug_trace", "trace_"];
+
+pub fn is_idempotent(method: &str) -> bool {
+    IDEMPOTENT_METHODS.contains(&method)
+        || IDEMPOTENT_PREFIXES.iter().any(|v| method.starts_with(v))
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RouteOptions {
+    pub split_policy: SplitPolicy,
+    // upstream requests are held for a random delay up to this, 0 to disable
+    pub mix_delay_ms: u64,
+    // decoy accounts queried along with each split balance query
+    pub decoys: usize,
+    // drawn as decoys, random addresses make up for the rest
+    pub decoy_addresses: Vec<String>,
+    // mean interval of background cover queries, 0 to disable
+    pub cover_interval_ms: u64,
+    // drop `from` of read-only calls whose result doesn't depend on it
+    pub strip_from: bool,
+    // names of sanitizer rules to run in order, `DEFAULT_RULES` if not set
+    pub rules: Option<Vec<String>>,
+    // calls split by the "abi" rule
+    pub abi_rules: Vec<AbiRuleConfig>,
+    // contracts known to the "account_relationship" and "multicall" rules
+    pub contracts: ContractRegistry,
+    // rounds failed sub-requests are sent again, `DEFAULT_SUB_RETRIES` if not set
+    pub sub_retries: Option<usize>,
+    // headers of upstream requests
+    pub headers: HeaderPolicy,
+    // accept http:// and ws:// upstreams, e.g. a local dev node
+    pub allow_plaintext: bool,
+    // upstream request timeouts
+    pub timeouts: TimeoutPolicy,
+    // times a failed upstream request of idempotent methods is sent to the
+    // next upstream, per client request, `DEFAULT_FAILOVERS` if not set
+    pub failovers: Option<usize>,
+    // health probes and circuit breaking of upstreams
+    pub health: HealthPolicy,
+    // how client requests are spread over upstreams
+    pub balance: BalancePolicy,
+}
+
+impl RouteOptions {
+    pub fn decoy_addresses(&self) -> Vec<H160> {
+        parse_addresses(&self.decoy_addresses)
+    }
+
+    pub fn sub_retries(&self) -> usize {
+        self.sub_retries.unwrap_or(DEFAULT_SUB_RETRIES)
+    }
+
+    pub fn failovers(&self) -> usize {
+        self.failovers.unwrap_or(DEFAULT_FAILOVERS)
+    }
+
+    pub fn rules(&self) -> Vec<&str> {
+        match &self.rules {
+            Some(v) => v.iter().map(|v| v.as_str()).collect(),
+            None => DEFAULT_RULES.to_vec(),
+        }
+    }
+}
+
+// a route in the static config, either
+//   "eth": "https://.."
+// or
+//   "eth": { "upstreams": ["https://..", "https://.."], "split_policy": "spread", "mix_delay_ms": 200 }
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum RouteConfig {
+    Uri(String),
+    Full {
+        upstreams: Vec<String>,
+        #[serde(flatten)]
+        options: RouteOptions,
+    },
+}
+
+#[derive(Clone)]
+pub struct JsonrpcRoute {
+    pub upstreams: Vec<Uri>,
+    pub options: RouteOptions,
+    pub abi_rules: Vec<AbiRule>, // parsed `options.abi_rules`
+}
+
+impl JsonrpcRoute {
+    pub fn single(uri: Uri) -> Self {
+        Self {
+            upstreams: vec![uri],
+            options: RouteOptions::default(),
+            abi_rules: vec![],
+        }
+    }
+
+    pub fn from_config(cfg: RouteConfig) -> Result<Self, String> {
+        let (upstreams, options, short) = match cfg {
+            RouteConfig::Uri(v) => (vec![v], RouteOptions::default(), true),
+            RouteConfig::Full { upstreams, options } => (upstreams, options, false),
+        };
+        if upstreams.is_empty() {
+            return Err("no upstream".into());
+        }
+        for v in &options.decoy_addresses {
+            if utils::parse_address(v).is_none() {
+                return Err(format!("invalid decoy address {}", v));
+            }
+        }
+        options.contracts.validate()?;
+        options.headers.validate()?;
+        options.timeouts.validate()?;
+        options.health.validate()?;
+        options.balance.validate(upstreams.len())?;
+        let uris = upstreams
+            .iter()
+            .map(|v| Uri::new(v).map_err(|e| format!("invalid upstream {}: {:?}", v, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !options.allow_plaintext {
+            if let Some(idx) = uris.iter().position(is_plaintext) {
+                // the short form has no room for the option
+                return Err(match short {
+                    true => format!(
+                        "plaintext upstream {} needs the full form: {{ \"upstreams\": [\"{}\"], \"allow_plaintext\": true }}",
+                        upstreams[idx], upstreams[idx]
+                    ),
+                    false => format!(
+                        "plaintext upstream {} needs allow_plaintext",
+                        upstreams[idx]
+                    ),
+                });
+            }
+        }
+        let abi_rules = options
+            .abi_rules
+            .iter()
+            .map(|v| AbiRule::new(v.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            upstreams: uris,
+            options,
+            abi_rules,
+        })
+    }
+
+    // upstreams http requests go to, plaintext ones only if the route allows
+    pub fn http_route(&self) -> Option<Self> {
+        match self.options.allow_plaintext {
+            true => self.filter_scheme(&["https", "http"]),
+            false => self.filter_scheme(&["https"]),
+        }
+    }
+
+    // upstreams ws connections go to, plaintext ones only if the route allows
+    pub fn ws_route(&self) -> Option<Self> {
+        match self.options.allow_plaintext {
+            true => self.filter_scheme(&["wss", "ws"]),
+            false => self.filter_scheme(&["wss"]),
+        }
+    }
+
+    // number of upstreams reached without tls
+    pub fn plaintext_upstreams(&self) -> usize {
+        self.upstreams.iter().filter(|v| is_plaintext(v)).count()
+    }
+
+    // keep upstreams of the given schemes only, `None` if nothing left
+    pub fn filter_scheme(&self, schemes: &[&str]) -> Option<Self> {
+        let upstreams = self
+            .upstreams
+            .iter()
+            .filter(|v| schemes.contains(&v.scheme()))
+            .cloned()
+            .collect::<Vec<_>>();
+        if upstreams.is_empty() {
+            return None;
+        }
+        Some(Self {
+            upstreams,
+            options: self.options.clone(),
+            abi_rules: self.abi_rules.clone(),
+        })
+    }
+}
+
+fn parse_addresses(vs: &[String]) -> Vec<H160> {
+    vs.iter().filter_map(|v| utils::parse_address(v)).collect()
+}
+
+fn is_plaintext(uri: &Uri) -> bool {
+    uri.scheme() == "http" || uri.scheme() == "ws"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full(upstreams: &[&str], allow_plaintext: bool) -> RouteConfig {
+        RouteConfig::Full {
+            upstreams: upstreams.iter().map(|v| v.to_string()).collect(),
+            options: RouteOptions {
+                allow_plaintext,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn plaintext_upstreams_need_opting_in() {
+        assert!(JsonrpcRoute::from_config(full(&["http://127.0.0.1:8545"], false)).is_err());
+        let route = JsonrpcRoute::from_config(full(&["http://127.0.0.1:8545"], true)).unwrap();
+        assert_eq!(route.plaintext_upstreams(), 1);
+        assert!(route.http_route().is_some());
+
+        let route = JsonrpcRoute::from_config(full(&["https://rpc-a.example"], false)).unwrap();
+        assert_eq!(route.plaintext_upstreams(), 0);
+        assert!(route.ws_route().is_none());
+    }
+
+    #[test]
+    fn short_form_refuses_plaintext() {
+        let cfg = serde_json::from_str(r#""http://127.0.0.1:8545""#).unwrap();
+        let err = JsonrpcRoute::from_config(cfg).err().unwrap();
+        assert!(err.contains(r#""allow_plaintext": true"#), "{}", err);
+
+        let cfg = serde_json::from_str(r#""wss://rpc.polkadot.io""#).unwrap();
+        let route = JsonrpcRoute::from_config(cfg).unwrap();
+        assert!(!route.options.allow_plaintext);
+        assert!(route.ws_route().is_some());
+    }
+}
@@ -0,0 +1,1491 @@
+use std::prelude::v1::*;
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+
+use base::time;
+use eth_types::{H160, SU256, U256};
+use hex::HexBytes;
+use jsonrpc::{Batchable, JsonrpcErrorObj, JsonrpcRawRequest, JsonrpcResponseRawResult};
+use serde_json::value::RawValue;
+
+use crate::abi_rule::{self, AbiOutput};
+use crate::block::BlockParam;
+use crate::rng::Rng;
+use crate::route::{HeaderPolicy, SplitPolicy};
+use crate::sanitizer::{
+    hex_result, sub_request_error, AccountRelationship, ContractDetection, DecomposedView,
+    Decomposition, Metadata, Normalization, RuleContext, SanitizedRequest, SanitizerRule,
+    SubRequest, Transform,
+};
+use crate::sol::{self, SolType, SolValue};
+use crate::{logs, multicall, normalize, utils};
+
+// 0x70a08231 is the 4 byte signature of balanceOf(address)
+const BALANCE_OF_SIG: [u8; 4] = [0x70, 0xa0, 0x82, 0x31];
+
+// 0xf0002ea9 is the 4 byte signature of balances(address[],address[])
+// https://www.4byte.directory/signatures/?bytes4_signature=0xf0002ea9
+const BALANCES_SIG: [u8; 4] = [0xf0, 0x00, 0x2e, 0xa9];
+
+const AGGREGATE_SIGS: [[u8; 4]; 4] = [
+    multicall::AGGREGATE_SIG,
+    multicall::TRY_AGGREGATE_SIG,
+    multicall::AGGREGATE3_SIG,
+    multicall::AGGREGATE3_VALUE_SIG,
+];
+
+// split calls described by `abi_rules` of the route
+pub struct AbiSignatureRule;
+
+impl SanitizerRule for AbiSignatureRule {
+    fn name(&self) -> &'static str {
+        "abi"
+    }
+
+    fn rewrite_request(&self, sr: &mut SanitizedRequest, ctx: &mut RuleContext) -> Vec<Transform> {
+        let mut trs = vec![];
+        let mut decisions = vec![];
+        for idx in 0..sr.len() {
+            if sr.decomposed_by(idx).is_some() {
+                continue;
+            }
+            for rule in &ctx.route.abi_rules {
+                let mut detection =
+                    match detect(sr.request(idx), rule.name(), &[rule.selector()], rule.to()) {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                if detection.decomposed {
+                    match rule.decompose(sr.request(idx)) {
+                        Some((subs, block, tr)) => {
+                            glog::debug!("abi rule {} applied", rule.name());
+                            let d = Decomposition {
+                                subs,
+                                block: Some(block),
+                                state: Box::new(rule.output()),
+                            };
+                            sr.decompose(idx, self.name(), d);
+                            trs.push(tr);
+                        }
+                        None => {
+                            detection.decomposed = false;
+                            detection.reason = "undecodable";
+                        }
+                    }
+                }
+                let decomposed = detection.decomposed;
+                decisions.push(detection);
+                if decomposed {
+                    break;
+                }
+            }
+        }
+        if !decisions.is_empty() {
+            trs.push(Transform::ContractDetection { decisions });
+        }
+        trs
+    }
+
+    fn sub_failed(&self, sub: &SubRequest, r: &JsonrpcResponseRawResult) -> bool {
+        balance_result_failed(sub, r)
+    }
+
+    fn rewrite_response(
+        &self,
+        elem: &DecomposedView,
+        rs: Vec<Option<JsonrpcResponseRawResult>>,
+    ) -> Result<Box<RawValue>, JsonrpcErrorObj> {
+        let output = match elem.state.downcast_ref::<AbiOutput>() {
+            Some(v) => *v,
+            None => return Err(JsonrpcErrorObj::unknown("unknown error")),
+        };
+        abi_rule::rewrite_response(output, elem.subs, rs).and_then(hex_result)
+    }
+}
+
+// split `balances(address[],address[])` of the balance checker contract
+// into one query per account
+pub struct AccountRelationshipRule;
+
+// accounts of a split balance query
+pub struct BalancesState {
+    pub users: Vec<H160>,
+    pub tokens: Vec<H160>,
+}
+
+impl SanitizerRule for AccountRelationshipRule {
+    fn name(&self) -> &'static str {
+        "account_relationship"
+    }
+
+    fn rewrite_request(&self, sr: &mut SanitizedRequest, ctx: &mut RuleContext) -> Vec<Transform> {
+        let known = ctx.route.options.contracts.balance_checkers();
+        let mut trs = vec![];
+        let mut decisions = vec![];
+        for idx in 0..sr.len() {
+            if sr.decomposed_by(idx).is_some() {
+                continue;
+            }
+            let mut detection =
+                match detect(sr.request(idx), "balance_checker", &[BALANCES_SIG], &known) {
+                    Some(v) => v,
+                    None => continue,
+                };
+            if detection.decomposed {
+                match decompose_balances(sr.request(idx)) {
+                    Some((state, subs, block, tr)) => {
+                        let d = Decomposition {
+                            subs,
+                            block: Some(block),
+                            state: Box::new(state),
+                        };
+                        sr.decompose(idx, self.name(), d);
+                        trs.push(tr);
+                    }
+                    None => {
+                        detection.decomposed = false;
+                        detection.reason = "undecodable";
+                    }
+                }
+            }
+            decisions.push(detection);
+        }
+        if !decisions.is_empty() {
+            trs.push(Transform::ContractDetection { decisions });
+        }
+        trs
+    }
+
+    fn sub_failed(&self, sub: &SubRequest, r: &JsonrpcResponseRawResult) -> bool {
+        balance_result_failed(sub, r)
+    }
+
+    fn rewrite_response(
+        &self,
+        elem: &DecomposedView,
+        rs: Vec<Option<JsonrpcResponseRawResult>>,
+    ) -> Result<Box<RawValue>, JsonrpcErrorObj> {
+        rewrite_account_relationship_response(elem.subs, rs).and_then(hex_result)
+    }
+}
+
+// split multicall aggregates whose sub-calls concern several accounts
+pub struct MulticallRule;
+
+impl SanitizerRule for MulticallRule {
+    fn name(&self) -> &'static str {
+        "multicall"
+    }
+
+    fn rewrite_request(&self, sr: &mut SanitizedRequest, ctx: &mut RuleContext) -> Vec<Transform> {
+        let known = ctx.route.options.contracts.multicalls();
+        let mut trs = vec![];
+        let mut decisions = vec![];
+        for idx in 0..sr.len() {
+            if sr.decomposed_by(idx).is_some() {
+                continue;
+            }
+            let mut detection = match detect(sr.request(idx), "multicall", &AGGREGATE_SIGS, &known)
+            {
+                Some(v) => v,
+                None => continue,
+            };
+            if detection.decomposed {
+                match decompose_multicall(sr.request(idx)) {
+                    Some((agg, subs, block, tr)) => {
+                        let d = Decomposition {
+                            subs,
+                            block: Some(block),
+                            state: Box::new(agg),
+                        };
+                        sr.decompose(idx, self.name(), d);
+                        trs.push(tr);
+                    }
+                    // single owner, or sub-calls we can't tell apart
+                    None => {
+                        detection.decomposed = false;
+                        detection.reason = "not splittable";
+                    }
+                }
+            }
+            decisions.push(detection);
+        }
+        if !decisions.is_empty() {
+            trs.push(Transform::ContractDetection { decisions });
+        }
+        trs
+    }
+
+    fn sub_failed(&self, _: &SubRequest, r: &JsonrpcResponseRawResult) -> bool {
+        match r {
+            JsonrpcResponseRawResult::Ok(v) => {
+                serde_json::from_raw_value::<HexBytes>(&v.result).is_err()
+            }
+            // a reverted sub-call is a result, the aggregate decides on it
+            JsonrpcResponseRawResult::Err(e) => utils::get_revert_data(&e.error).is_none(),
+        }
+    }
+
+    fn rewrite_response(
+        &self,
+        elem: &DecomposedView,
+        rs: Vec<Option<JsonrpcResponseRawResult>>,
+    ) -> Result<Box<RawValue>, JsonrpcErrorObj> {
+        let agg = match elem.state.downcast_ref::<multicall::Aggregate>() {
+            Some(v) => v,
+            None => return Err(JsonrpcErrorObj::unknown("unknown error")),
+        };
+        let block_number = elem.block_number.unwrap_or_default();
+        rewrite_multicall_response(agg, block_number, rs).and_then(hex_result)
+    }
+}
+
+// mix decoy accounts into split balance queries, `decoys` of the route per
+// request
+pub struct DecoyRule;
+
+impl SanitizerRule for DecoyRule {
+    fn name(&self) -> &'static str {
+        "decoy"
+    }
+
+    fn rewrite_request(&self, sr: &mut SanitizedRequest, ctx: &mut RuleContext) -> Vec<Transform> {
+        let k = ctx.route.options.decoys;
+        if k == 0 {
+            return vec![];
+        }
+        let configured = ctx.route.options.decoy_addresses();
+        let pool = &mut *ctx.decoys;
+        let now = time::Date::from(time::now()).to_string();
+        let mut protected = vec![];
+        for idx in 0..sr.len() {
+            let (users, tokens) = match sr.state::<BalancesState>(idx) {
+                Some(v) => (v.users.clone(), v.tokens.clone()),
+                None => continue,
+            };
+            let subs = match sr.subs_mut(idx) {
+                Some(v) => v,
+                None => continue,
+            };
+            for decoy in pool.sample(k, &configured, &users) {
+                for (mut sub, rel) in balance_subs(&decoy, &tokens, &now) {
+                    sub.decoy = true;
+                    // keep the real ones in order, results are matched by position
+                    let pos = pool.rng().below(subs.len() as u64 + 1) as usize;
+                    subs.insert(pos, sub);
+                    protected.push(rel);
+                }
+            }
+        }
+        if protected.is_empty() {
+            return vec![];
+        }
+        vec![Transform::Decoy {
+            protected,
+            unprotected: vec![],
+        }]
+    }
+}
+
+// calls whose first param is the account they are about
+const ACCOUNT_METHODS: [&str; 5] = [
+    "eth_getBalance",
+    "eth_getTransactionCount",
+    "eth_getCode",
+    "eth_getStorageAt",
+    "eth_getProof",
+];
+
+// a batch of calls about several accounts links them as much as one
+// `balances()` call does. send the calls of each account apart, unless the
+// route sends everything in one batch, where nothing can be done
+pub struct AccountLinkageRule;
+
+impl SanitizerRule for AccountLinkageRule {
+    fn name(&self) -> &'static str {
+        "account_linkage"
+    }
+
+    fn rewrite_request(&self, sr: &mut SanitizedRequest, ctx: &mut RuleContext) -> Vec<Transform> {
+        let mut calls = vec![];
+        for idx in 0..sr.len() {
+            if sr.decomposed_by(idx).is_some() {
+                continue;
+            }
+            let req = sr.request(idx);
+            let method = match ACCOUNT_METHODS.iter().find(|v| **v == req.method) {
+                Some(v) => *v,
+                None => continue,
+            };
+            let account = utils::get_jsonrpc_param(req, 0)
+                .and_then(|v| v.as_str().and_then(utils::parse_address));
+            if let Some(account) = account {
+                calls.push((idx, method, account));
+            }
+        }
+        let mut accounts = vec![];
+        for (_, _, account) in &calls {
+            if !accounts.contains(account) {
+                accounts.push(account.clone());
+            }
+        }
+        if accounts.len() < 2 {
+            return vec![];
+        }
+        // groups are ignored, the accounts still go out together
+        if ctx.route.options.split_policy == SplitPolicy::Batch {
+            return vec![];
+        }
+
+        let now = time::Date::from(time::now()).to_string();
+        let mut protected = vec![];
+        let mut methods = vec![];
+        for (idx, method, account) in calls {
+            let acct = format!("{:?}", account);
+            sr.set_group(idx, acct.clone());
+            protected.push(AccountRelationship {
+                accounts: vec![acct],
+                method,
+                params: vec![],
+                time: now.clone(),
+            });
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
+        }
+        vec![Transform::AccountRelationship {
+            protected,
+            unprotected: AccountRelationship {
+                accounts: accounts.iter().map(|v| format!("{:?}", v)).collect(),
+                method: "batch",
+                params: methods.into_iter().map(|v| v.to_owned()).collect(),
+                time: now,
+            },
+        }]
+    }
+}
+
+// put params of requests forwarded as is into one canonical form
+pub struct FingerprintRule;
+
+impl SanitizerRule for FingerprintRule {
+    fn name(&self) -> &'static str {
+        "fingerprint"
+    }
+
+    fn rewrite_request(&self, sr: &mut SanitizedRequest, ctx: &mut RuleContext) -> Vec<Transform> {
+        normalize_requests(sr, ctx.route.options.strip_from)
+    }
+}
+
+// filters installed by `LogFilterRule` kept at most, the oldest are forgotten
+const MAX_LOG_FILTERS: usize = 4096;
+
+// query logs of several addresses or owners apart, see `logs`. a filter
+// broken down lives on the upstreams as several filters under an id of ours,
+// known to the route it was installed by only
+pub struct LogFilterRule {
+    filters: RefCell<LogFilters>,
+}
+
+struct LogFilters {
+    entries: BTreeMap<(String, String), LogFilter>, // (rpc path, id)
+    order: VecDeque<(String, String)>,
+    rng: Rng,
+}
+
+// the original filter, and (upstream, filter id) of each part
+struct LogFilter {
+    filter: serde_json::Value,
+    parts: Vec<(usize, String)>,
+}
+
+enum LogsState {
+    Logs(serde_json::Value),              // of the original filter
+    NewFilter(String, serde_json::Value), // rpc path installed by
+    Uninstall,
+}
+
+impl LogFilters {
+    fn insert(&mut self, rpc_path: &str, filter: LogFilter) -> String {
+        let id = format!("0x{:016x}{:016x}", self.rng.next_u64(), self.rng.next_u64());
+        let key = (rpc_path.to_owned(), id.clone());
+        self.entries.insert(key.clone(), filter);
+        self.order.push_back(key);
+        while self.order.len() > MAX_LOG_FILTERS {
+            if let Some(v) = self.order.pop_front() {
+                self.entries.remove(&v);
+            }
+        }
+        id
+    }
+
+    fn get(&self, rpc_path: &str, id: String) -> Option<&LogFilter> {
+        self.entries.get(&(rpc_path.to_owned(), id))
+    }
+
+    fn remove(&mut self, rpc_path: &str, id: String) -> Option<LogFilter> {
+        let key = (rpc_path.to_owned(), id);
+        self.order.retain(|v| *v != key);
+        self.entries.remove(&key)
+    }
+}
+
+impl LogFilterRule {
+    pub fn new() -> Self {
+        Self {
+            filters: RefCell::new(LogFilters {
+                entries: BTreeMap::new(),
+                order: VecDeque::new(),
+                rng: Rng::from_entropy(),
+            }),
+        }
+    }
+}
+
+impl SanitizerRule for LogFilterRule {
+    fn name(&self) -> &'static str {
+        "logs"
+    }
+
+    fn rewrite_request(&self, sr: &mut SanitizedRequest, ctx: &mut RuleContext) -> Vec<Transform> {
+        let upstreams = ctx.route.upstreams.len().max(1);
+        let mut filters = self.filters.borrow_mut();
+        let now = time::Date::from(time::now()).to_string();
+        let mut trs = vec![];
+        for idx in 0..sr.len() {
+            if sr.decomposed_by(idx).is_some() {
+                continue;
+            }
+            let req = sr.request(idx);
+            let method = req.method.clone();
+            let sub = |params: serde_json::Value, upstream: Option<usize>| SubRequest {
+                method: method.clone(),
+                params: vec![params],
+                decoy: false,
+                upstream,
+            };
+            let filter_id =
+                || utils::get_jsonrpc_param(req, 0).and_then(|v| v.as_str().map(|v| v.to_owned()));
+            let (subs, state) = match method.as_str() {
+                "eth_getLogs" | "eth_newFilter" => {
+                    let filter = match logs::request_filter(req) {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    let parts = match logs::split_filter(&filter) {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    let tr = Transform::AccountRelationship {
+                        protected: parts
+                            .iter()
+                            .map(|v| logs_relationship(&method, v, &now))
+                            .collect(),
+                        unprotected: logs_relationship(&method, &filter, &now),
+                    };
+                    trs.push(tr);
+                    if method == "eth_getLogs" {
+                        let subs = parts.into_iter().map(|v| sub(v, None)).collect();
+                        (subs, LogsState::Logs(filter))
+                    } else {
+                        // spread the parts over upstreams, each lives where installed
+                        let offset = filters.rng.below(upstreams as u64) as usize;
+                        let subs = parts
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, v)| sub(v, Some((offset + i) % upstreams)))
+                            .collect();
+                        (subs, LogsState::NewFilter(ctx.rpc_path.to_owned(), filter))
+                    }
+                }
+                "eth_getFilterChanges" | "eth_getFilterLogs" => {
+                    let f = match filter_id().and_then(|v| filters.get(ctx.rpc_path, v)) {
+                        Some(v) => v,
+                        // not ours, e.g. a block filter
+                        None => continue,
+                    };
+                    let subs = f
+                        .parts
+                        .iter()
+                        .map(|(u, v)| sub(serde_json::json!(v), Some(*u)))
+                        .collect();
+                    (subs, LogsState::Logs(f.filter.clone()))
+                }
+                "eth_uninstallFilter" => {
+                    let f = match filter_id().and_then(|v| filters.remove(ctx.rpc_path, v)) {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    let subs = f
+                        .parts
+                        .into_iter()
+                        .map(|(u, v)| sub(serde_json::json!(v), Some(u)))
+                        .collect();
+                    (subs, LogsState::Uninstall)
+                }
+                _ => continue,
+            };
+            let d = Decomposition {
+                subs,
+                block: None,
+                state: Box::new(state),
+            };
+            sr.decompose(idx, self.name(), d);
+        }
+        trs
+    }
+
+    fn sub_failed(&self, sub: &SubRequest, r: &JsonrpcResponseRawResult) -> bool {
+        let v = match r {
+            JsonrpcResponseRawResult::Ok(v) => v,
+            JsonrpcResponseRawResult::Err(_) => return true,
+        };
+        match sub.method.as_str() {
+            "eth_newFilter" => serde_json::from_raw_value::<String>(&v.result).is_err(),
+            "eth_uninstallFilter" => false,
+            _ => serde_json::from_raw_value::<Vec<serde_json::Value>>(&v.result).is_err(),
+        }
+    }
+
+    // the parts of a broken down filter installed before another failed
+    fn rollback(
+        &self,
+        subs: &[SubRequest],
+        rs: &[Option<JsonrpcResponseRawResult>],
+    ) -> Vec<SubRequest> {
+        subs.iter()
+            .zip(rs)
+            .filter(|(sub, _)| sub.method == "eth_newFilter")
+            .filter_map(|(sub, r)| {
+                let id = match r {
+                    Some(JsonrpcResponseRawResult::Ok(v)) => {
+                        serde_json::from_raw_value::<String>(&v.result).ok()?
+                    }
+                    _ => return None,
+                };
+                Some(SubRequest {
+                    method: "eth_uninstallFilter".into(),
+                    params: vec![serde_json::json!(id)],
+                    decoy: false,
+                    upstream: sub.upstream,
+                })
+            })
+            .collect()
+    }
+
+    fn rewrite_response(
+        &self,
+        elem: &DecomposedView,
+        rs: Vec<Option<JsonrpcResponseRawResult>>,
+    ) -> Result<Box<RawValue>, JsonrpcErrorObj> {
+        let err = sub_request_error;
+        let state = match elem.state.downcast_ref::<LogsState>() {
+            Some(v) => v,
+            None => return Err(JsonrpcErrorObj::unknown("unknown error")),
+        };
+        let mut results = vec![];
+        for (sub, r) in elem.subs.iter().zip(rs) {
+            match r {
+                Some(r) if !self.sub_failed(sub, &r) => {
+                    if let JsonrpcResponseRawResult::Ok(v) = r {
+                        results.push(v.result);
+                    }
+                }
+                _ => {
+                    glog::error!("protect_logs_error: remote_response failed");
+                    return Err(err());
+                }
+            }
+        }
+        let result = match state {
+            LogsState::Logs(filter) => {
+                let lists = results
+                    .iter()
+                    .filter_map(|v| serde_json::from_raw_value(v).ok())
+                    .collect();
+                serde_json::to_raw_value(&logs::merge_logs(filter, lists))
+            }
+            LogsState::NewFilter(rpc_path, filter) => {
+                let parts = elem
+                    .subs
+                    .iter()
+                    .zip(&results)
+                    .filter_map(|(sub, v)| {
+                        let id = serde_json::from_raw_value::<String>(v).ok()?;
+                        Some((sub.upstream.unwrap_or_default(), id))
+                    })
+                    .collect();
+                let id = self.filters.borrow_mut().insert(
+                    rpc_path,
+                    LogFilter {
+                        filter: filter.clone(),
+                        parts,
+                    },
+                );
+                serde_json::to_raw_value(&id)
+            }
+            LogsState::Uninstall => serde_json::to_raw_value(&true),
+        };
+        result.map_err(|_| err())
+    }
+}
+
+// accounts a log filter is about, as an upstream sees them together
+fn logs_relationship(method: &str, filter: &serde_json::Value, now: &str) -> AccountRelationship {
+    AccountRelationship {
+        accounts: logs::filter_accounts(filter),
+        method: match method {
+            "eth_newFilter" => "eth_newFilter",
+            _ => "eth_getLogs",
+        },
+        params: vec![],
+        time: now.to_owned(),
+    }
+}
+
+// replace ip and user-agent of the client with ours
+pub struct MetadataRule;
+
+impl SanitizerRule for MetadataRule {
+    fn name(&self) -> &'static str {
+        "metadata"
+    }
+
+    fn rewrite_request(&self, _: &mut SanitizedRequest, ctx: &mut RuleContext) -> Vec<Transform> {
+        let client = match ctx.client {
+            Some(v) => v,
+            None => return vec![],
+        };
+        let policy = &ctx.route.options.headers;
+        let sent = policy.sent();
+        let removed = client
+            .headers
+            .iter()
+            .filter(|v| !sent.iter().any(|s| s.eq_ignore_ascii_case(v)))
+            .cloned()
+            .collect();
+        let now = time::Date::from(time::now()).to_string();
+        vec![Transform::Metadata {
+            protected: Metadata {
+                ip: ctx.egress_ip.unwrap_or("N/A").into(),
+                ua: ctx.ua.into(),
+                headers: sent,
+                removed,
+                time: now.clone(),
+            },
+            unprotected: Metadata {
+                ip: client.ip.clone(),
+                ua: client.ua.clone(),
+                headers: client.headers.clone(),
+                removed: vec![],
+                time: now,
+            },
+        }]
+    }
+}
+
+fn decompose_balances(
+    req: &JsonrpcRawRequest,
+) -> Option<(BalancesState, Vec<SubRequest>, BlockParam, Transform)> {
+    macro_rules! decode_fail {
+        ($msg:expr) => {{
+            glog::warn!("protect_account_relationship abort: {}", $msg);
+            return None;
+        }};
+    }
+
+    if req.method != "eth_call" {
+        return None;
+    }
+
+    let calldata = utils::get_eth_call_data_from_jsonrpc(&req)?;
+    let data = calldata.as_bytes();
+    if !sol::func_sig_matches(data, &BALANCES_SIG) {
+        return None;
+    }
+
+    let address_array = || SolType::Array(Box::new(SolType::Address));
+    let args = match sol::decode(&[address_array(), address_array()], &data[4..]) {
+        Ok(v) => v,
+        Err(e) => decode_fail!(format!("{:?}", e)),
+    };
+    let addresses = |v: &SolValue| -> Vec<H160> {
+        v.as_list()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|v| v.as_address().cloned())
+            .collect()
+    };
+    let users = addresses(&args[0]);
+    let tokens = addresses(&args[1]);
+    let block = match BlockParam::parse(utils::get_jsonrpc_param(&req, 1).as_ref()) {
+        Some(v) => v,
+        None => decode_fail!("block"),
+    };
+
+    let accts = users.iter().map(|v| format!("{:?}", v)).collect::<Vec<_>>();
+
+    // one sub-request per (user, token), user-major like the contract's result
+    let mut reqs = vec![];
+    let mut protected = vec![];
+    let now = time::Date::from(time::now()).to_string();
+    for user in &users {
+        for (sub, rel) in balance_subs(user, &tokens, &now) {
+            reqs.push(sub);
+            protected.push(rel);
+        }
+    }
+    if reqs.is_empty() {
+        decode_fail!("empty users or tokens");
+    }
+    let tr = Transform::AccountRelationship {
+        protected,
+        unprotected: AccountRelationship {
+            accounts: accts,
+            method: "eth_call",
+            params: vec!["0xf0002ea9".into(), block.describe()],
+            time: now.clone(),
+        },
+    };
+    Some((BalancesState { users, tokens }, reqs, block, tr))
+}
+
+// an error, or a result that doesn't read as a balance (or return data)
+fn balance_result_failed(sub: &SubRequest, r: &JsonrpcResponseRawResult) -> bool {
+    let v = match r {
+        JsonrpcResponseRawResult::Ok(v) => v,
+        JsonrpcResponseRawResult::Err(_) => return true,
+    };
+    match sub.method.as_str() {
+        "eth_getBalance" => serde_json::from_raw_value::<SU256>(&v.result).is_err(),
+        _ => serde_json::from_raw_value::<HexBytes>(&v.result).is_err(),
+    }
+}
+
+// `eth_getBalance` for the native token and `balanceOf(address)` for others
+fn balance_subs(user: &H160, tokens: &[H160], now: &str) -> Vec<(SubRequest, AccountRelationship)> {
+    let acct = format!("{:?}", user);
+    let mut subs = vec![];
+    for token in tokens {
+        let (sub, method, params) = if token.is_zero() {
+            let sub = SubRequest {
+                method: "eth_getBalance".into(),
+                params: vec![serde_json::json!(acct)],
+                decoy: false,
+                upstream: None,
+            };
+            (sub, "eth_getBalance", vec![])
+        } else {
+            let token = format!("{:?}", token);
+            let mut data = BALANCE_OF_SIG.to_vec();
+            data.extend_from_slice(&sol::encode_address(user));
+            let txn = serde_json::json!({
+                "to": token,
+                "data": String::from("0x") + &hex::encode(data),
+            });
+            let sub = SubRequest {
+                method: "eth_call".into(),
+                params: vec![txn],
+                decoy: false,
+                upstream: None,
+            };
+            (sub, "eth_call", vec!["0x70a08231".into(), token])
+        };
+        let rel = AccountRelationship {
+            accounts: vec![acct.clone()],
+            method,
+            params,
+            time: now.to_owned(),
+        };
+        subs.push((sub, rel));
+    }
+    subs
+}
+
+fn decompose_multicall(
+    req: &JsonrpcRawRequest,
+) -> Option<(multicall::Aggregate, Vec<SubRequest>, BlockParam, Transform)> {
+    macro_rules! decode_fail {
+        ($msg:expr) => {{
+            glog::warn!("protect_multicall_relationship abort: {}", $msg);
+            return None;
+        }};
+    }
+
+    if req.method != "eth_call" {
+        return None;
+    }
+
+    let calldata = utils::get_eth_call_data_from_jsonrpc(&req)?;
+    let agg = match multicall::Aggregate::decode(calldata.as_bytes()) {
+        Ok(Some(v)) => v,
+        Ok(None) => return None,
+        Err(e) => decode_fail!(format!("{:?}", e)),
+    };
+    let block = match BlockParam::parse(utils::get_jsonrpc_param(&req, 1).as_ref()) {
+        Some(v) => v,
+        None => decode_fail!("block"),
+    };
+
+    // only split plain reads, anything else may depend on being called together
+    let mut owners = vec![];
+    for call in &agg.calls {
+        if !call.value.is_zero() {
+            decode_fail!("sub-call with value");
+        }
+        match call.owner() {
+            Some(Some(owner)) => {
+                if !owners.contains(&owner) {
+                    owners.push(owner);
+                }
+            }
+            Some(None) => {}
+            None => decode_fail!("unknown sub-call"),
+        }
+    }
+    if owners.len() < 2 {
+        return None;
+    }
+
+    let now = time::Date::from(time::now()).to_string();
+    let mut reqs = vec![];
+    let mut protected = vec![];
+    for call in &agg.calls {
+        let target = format!("{:?}", call.target);
+        let sig = String::from("0x") + &hex::encode(&call.data[..4]);
+        let txn = serde_json::json!({
+            "to": target,
+            "data": String::from("0x") + &hex::encode(&call.data),
+        });
+        reqs.push(SubRequest {
+            method: "eth_call".into(),
+            params: vec![txn],
+            decoy: false,
+            upstream: None,
+        });
+        protected.push(AccountRelationship {
+            accounts: match call.owner() {
+                Some(Some(owner)) => vec![format!("{:?}", owner)],
+                _ => vec![],
+            },
+            method: "eth_call",
+            params: vec![sig, target],
+            time: now.clone(),
+        });
+    }
+
+    let tr = Transform::AccountRelationship {
+        protected,
+        unprotected: AccountRelationship {
+            accounts: owners.iter().map(|v| format!("{:?}", v)).collect(),
+            method: "eth_call",
+            params: vec![agg.kind.sig().into(), block.describe()],
+            time: now,
+        },
+    };
+    Some((agg, reqs, block, tr))
+}
+
+// an `eth_call` to one of `sigs` of the `contract` kind, to be broken down
+// only if it goes to a `known` deployment. `None` if `req` is no such call
+fn detect(
+    req: &JsonrpcRawRequest,
+    contract: &str,
+    sigs: &[[u8; 4]],
+    known: &[H160],
+) -> Option<ContractDetection> {
+    if req.method != "eth_call" {
+        return None;
+    }
+    let calldata = utils::get_eth_call_data_from_jsonrpc(req)?;
+    let sig = sigs
+        .iter()
+        .find(|v| sol::func_sig_matches(calldata.as_bytes(), &v[..]))?;
+    let to = utils::get_eth_call_to_from_jsonrpc(req);
+    let registered = to.map(|v| known.contains(&v)).unwrap_or(false);
+    let detection = ContractDetection {
+        contract: contract.to_owned(),
+        to: to.map(|v| format!("{:?}", v)).unwrap_or_default(),
+        selector: String::from("0x") + &hex::encode(&sig[..]),
+        decomposed: registered,
+        reason: if registered {
+            ""
+        } else {
+            "unregistered contract"
+        },
+        time: time::Date::from(time::now()).to_string(),
+    };
+    glog::info!(
+        "{} call to {}: decomposed={}",
+        contract,
+        detection.to,
+        detection.decomposed
+    );
+    Some(detection)
+}
+
+// put params of requests forwarded as is into one canonical form
+fn normalize_requests(sr: &mut SanitizedRequest, strip_from: bool) -> Vec<Transform> {
+    let mut protected = vec![];
+    let mut unprotected = vec![];
+    for idx in 0..sr.len() {
+        if sr.decomposed_by(idx).is_some() {
+            // sub-requests are built by ourselves
+            continue;
+        }
+        let req = sr.request(idx);
+        let mut params = match serde_json::from_raw_value::<Vec<serde_json::Value>>(&req.params) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let rules = normalize::normalize(&req.method, &mut params, strip_from);
+        if rules.is_empty() {
+            continue;
+        }
+        let raw = match serde_json::to_raw_value(&params) {
+            Ok(v) => v,
+            Err(e) => {
+                glog::error!("normalize request[{}] fail: {:?}", req.method, e);
+                continue;
+            }
+        };
+        unprotected.push(Normalization {
+            method: req.method.clone(),
+            params: req.params.get().to_owned(),
+            rules: vec![],
+        });
+        protected.push(Normalization {
+            method: req.method.clone(),
+            params: raw.get().to_owned(),
+            rules,
+        });
+        sr.set_params(idx, raw);
+    }
+    if protected.is_empty() {
+        return vec![];
+    }
+    vec![Transform::Normalization {
+        protected,
+        unprotected,
+    }]
+}
+
+// for requests that don't go through the registry, i.e. relayed over ws
+pub fn protect_fingerprint(mut sr: SanitizedRequest, strip_from: bool) -> SanitizedRequest {
+    let tr = normalize_requests(&mut sr, strip_from);
+    if !tr.is_empty() {
+        sr.tr.extend(tr);
+        sr.flatten();
+    }
+    sr
+}
+
+// a lone balance query with no client behind it, sent to keep the upstream
+// from telling real traffic by its rate
+pub(crate) fn cover_request(
+    addr: &H160,
+    policy: &HeaderPolicy,
+    ua: &str,
+) -> Option<SanitizedRequest> {
+    let acct = format!("{:?}", addr);
+    let req = match JsonrpcRawRequest::new(0, "eth_getBalance", &(&acct, "latest")) {
+        Ok(v) => v,
+        Err(e) => {
+            glog::error!("build cover request fail: {:?}", e);
+            return None;
+        }
+    };
+    let mut sr = SanitizedRequest::new(Batchable::Single(req));
+    let now = time::Date::from(time::now()).to_string();
+    let metadata = || Metadata {
+        ip: "N/A".into(),
+        ua: ua.into(),
+        headers: policy.sent(),
+        removed: vec![],
+        time: now.clone(),
+    };
+    sr.tr.push(Transform::Metadata {
+        protected: metadata(),
+        unprotected: metadata(),
+    });
+    sr.tr.push(Transform::Decoy {
+        protected: vec![AccountRelationship {
+            accounts: vec![acct],
+            method: "eth_getBalance",
+            params: vec![],
+            time: now.clone(),
+        }],
+        unprotected: vec![],
+    });
+    Some(sr)
+}
+
+fn rewrite_account_relationship_response(
+    subs: &[SubRequest],
+    rs: Vec<Option<JsonrpcResponseRawResult>>,
+) -> Result<Vec<u8>, JsonrpcErrorObj> {
+    let err = sub_request_error;
+
+    // get balances from batch, `eth_getBalance` for native token and
+    // `balanceOf(address)` for others
+    let mut balances = vec![];
+    for (req, r) in subs.iter().zip(rs) {
+        if req.decoy {
+            continue;
+        }
+        let v = match r {
+            Some(JsonrpcResponseRawResult::Ok(v)) => v,
+            Some(JsonrpcResponseRawResult::Err(e)) => {
+                glog::error!(
+                    "protect_account_error: remote_response contains error {:?}",
+                    e.error
+                );
+                return Err(err());
+            }
+            None => {
+                glog::error!("protect_account_error: remote_response missing");
+                return Err(err());
+            }
+        };
+        let balance = match req.method.as_str() {
+            "eth_getBalance" => serde_json::from_raw_value::<SU256>(&v.result)
+                .ok()
+                .map(|v| *v),
+            _ => serde_json::from_raw_value::<HexBytes>(&v.result)
+                .ok()
+                .map(|v| {
+                    // non-contract token returns empty data, the checker
+                    // contract reports 0 in that case
+                    sol::decode_uint256(v.as_bytes()).unwrap_or_default()
+                }),
+        };
+        match balance {
+            Some(v) => balances.push(v),
+            None => {
+                glog::error!("protected_account_error: deser jsonrpc");
+                return Err(err());
+            }
+        }
+    }
+    // encode balances
+    Ok(sol::encode_uint256_array(&balances))
+}
+
+fn rewrite_multicall_response(
+    agg: &multicall::Aggregate,
+    block_number: U256,
+    rs: Vec<Option<JsonrpcResponseRawResult>>,
+) -> Result<Vec<u8>, JsonrpcErrorObj> {
+    let err = sub_request_error;
+
+    let mut results = vec![];
+    for (call, r) in agg.calls.iter().zip(rs) {
+        match r {
+            Some(JsonrpcResponseRawResult::Ok(v)) => {
+                match serde_json::from_raw_value::<HexBytes>(&v.result) {
+                    Ok(v) => results.push((true, v.as_bytes().to_vec())),
+                    Err(_) => {
+                        glog::error!("protect_multicall_error: deser jsonrpc");
+                        return Err(err());
+                    }
+                }
+            }
+            Some(JsonrpcResponseRawResult::Err(e)) => {
+                let revert_data = match utils::get_revert_data(&e.error) {
+                    Some(v) => v,
+                    None => {
+                        glog::error!(
+                            "protect_multicall_error: remote_response contains error {:?}",
+                            e.error
+                        );
+                        return Err(err());
+                    }
+                };
+                if agg.must_succeed(call) {
+                    // the whole aggregate reverts, as Multicall3 does
+                    return Err(JsonrpcErrorObj::error(
+                        3,
+                        "execution reverted: Multicall3: call failed".into(),
+                    ));
+                }
+                results.push((false, revert_data));
+            }
+            None => {
+                glog::error!("protect_multicall_error: remote_response missing");
+                return Err(err());
+            }
+        }
+    }
+    Ok(agg.encode_result(block_number, &results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoy::DecoyPool;
+    use crate::route::{
+        JsonrpcRoute, RouteConfig, RouteOptions, BALANCE_CHECKER_ADDRESS, UPSTREAM_UA,
+    };
+    use crate::sanitizer::RuleRegistry;
+
+    fn route(options: RouteOptions) -> JsonrpcRoute {
+        JsonrpcRoute::from_config(RouteConfig::Full {
+            upstreams: vec![
+                "https://rpc-a.example".into(),
+                "https://rpc-b.example".into(),
+            ],
+            options,
+        })
+        .unwrap()
+    }
+
+    fn address(byte: u8) -> H160 {
+        H160::from_slice(&[byte; 20])
+    }
+
+    fn balances_call(users: &[H160]) -> Batchable<JsonrpcRawRequest> {
+        balances_call_at(users, "latest")
+    }
+
+    fn balances_call_at(users: &[H160], block: &str) -> Batchable<JsonrpcRawRequest> {
+        let mut data = BALANCES_SIG.to_vec();
+        data.extend(sol::encode(&[
+            SolValue::Array(users.iter().cloned().map(SolValue::Address).collect()),
+            SolValue::Array(vec![SolValue::Address(address(0xee))]),
+        ]));
+        let txn = serde_json::json!({
+            "to": BALANCE_CHECKER_ADDRESS,
+            "data": String::from("0x") + &hex::encode(data),
+        });
+        Batchable::Single(JsonrpcRawRequest::new(1, "eth_call", &(txn, block)).unwrap())
+    }
+
+    // what the client is shown in demo mode
+    fn report(
+        rules: &RuleRegistry,
+        route: &JsonrpcRoute,
+        decoys: &mut DecoyPool,
+        req: Batchable<JsonrpcRawRequest>,
+    ) -> String {
+        let mut ctx = RuleContext {
+            route,
+            rpc_path: "/eth",
+            client: None,
+            decoys,
+            egress_ip: None,
+            ua: UPSTREAM_UA,
+        };
+        let sr = rules.sanitize(SanitizedRequest::new(req), &route.options.rules(), &mut ctx);
+        serde_json::to_string(&sr.tr).unwrap().to_lowercase()
+    }
+
+    #[test]
+    fn decoys_never_show_other_clients() {
+        let rules = RuleRegistry::with_defaults();
+        let route = route(RouteOptions {
+            decoys: 3,
+            ..Default::default()
+        });
+        let mut decoys = DecoyPool::new(Rng::new(7));
+        let alice = format!("{:?}", address(0xaa));
+        let bob = format!("{:?}", address(0xbb));
+        for _ in 0..16 {
+            let tr = report(&rules, &route, &mut decoys, balances_call(&[address(0xaa)]));
+            assert!(tr.contains(&alice));
+            assert!(tr.contains("\"decoy\""));
+            assert!(!tr.contains(&bob));
+
+            let tr = report(&rules, &route, &mut decoys, balances_call(&[address(0xbb)]));
+            assert!(tr.contains(&bob));
+            assert!(!tr.contains(&alice));
+        }
+    }
+
+    #[test]
+    fn decoys_come_from_configured_addresses_first() {
+        let mut decoys = DecoyPool::new(Rng::new(7));
+        let configured = vec![address(0x01), address(0x02)];
+        let picked = decoys.sample(4, &configured, &[address(0x02)]);
+        assert_eq!(picked.len(), 4);
+        assert!(picked.contains(&address(0x01)));
+        assert!(!picked.contains(&address(0x02)));
+    }
+
+    fn balances_of(users: &[H160]) -> Batchable<JsonrpcRawRequest> {
+        let reqs = users
+            .iter()
+            .enumerate()
+            .map(|(idx, user)| {
+                let params = (format!("{:?}", user), "latest");
+                JsonrpcRawRequest::new(idx as u64, "eth_getBalance", &params).unwrap()
+            })
+            .collect();
+        Batchable::Batch(reqs)
+    }
+
+    #[test]
+    fn account_linkage_only_reported_when_sent_apart() {
+        let rules = RuleRegistry::with_defaults();
+        let mut decoys = DecoyPool::new(Rng::new(7));
+        let users = [address(0xaa), address(0xbb)];
+        let linked = |policy, users: &[H160], decoys: &mut DecoyPool| {
+            let route = route(RouteOptions {
+                split_policy: policy,
+                ..Default::default()
+            });
+            report(&rules, &route, decoys, balances_of(users)).contains("\"accountrelationship\"")
+        };
+        assert!(linked(SplitPolicy::Spread, &users, &mut decoys));
+        assert!(linked(SplitPolicy::Separate, &users, &mut decoys));
+        // groups are ignored in one batch, nothing to report
+        assert!(!linked(SplitPolicy::Batch, &users, &mut decoys));
+        // one account links nothing
+        assert!(!linked(SplitPolicy::Spread, &users[..1], &mut decoys));
+    }
+
+    fn scanner_rule(to: Vec<String>) -> abi_rule::AbiRuleConfig {
+        serde_json::from_value(serde_json::json!({
+            "name": "scanner",
+            "selector": "0xf0002ea9",
+            "inputs": ["address[]", "address[]"],
+            "to": to,
+            "accounts": 0,
+            "items": 1,
+            "call": { "method": "eth_call", "to": "item", "selector": "0x70a08231", "args": ["account"] },
+            "output": "uint256[]"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn abi_rule_needs_to() {
+        assert!(abi_rule::AbiRule::new(scanner_rule(vec![])).is_err());
+        let to = format!("{:?}", address(0x11));
+        assert!(abi_rule::AbiRule::new(scanner_rule(vec![to])).is_ok());
+    }
+
+    #[test]
+    fn abi_rule_only_breaks_down_registered_contracts() {
+        let rules = RuleRegistry::with_defaults();
+        let mut decoys = DecoyPool::new(Rng::new(7));
+        let route = route(RouteOptions {
+            rules: Some(vec!["abi".into()]),
+            abi_rules: vec![scanner_rule(vec![BALANCE_CHECKER_ADDRESS.into()])],
+            ..Default::default()
+        });
+        let users = [address(0xaa), address(0xbb)];
+        let tr = report(&rules, &route, &mut decoys, balances_call(&users));
+        assert!(tr.contains("\"contractdetection\""));
+        assert!(tr.contains("\"contract\":\"scanner\""));
+        assert!(tr.contains("\"decomposed\":true"));
+
+        // the same selector to another contract
+        let mut req = balances_call(&users);
+        if let Batchable::Single(v) = &mut req {
+            let txn = serde_json::json!({
+                "to": format!("{:?}", address(0x11)),
+                "data": String::from("0x")
+                    + &hex::encode(utils::get_eth_call_data_from_jsonrpc(v).unwrap().as_bytes()),
+            });
+            *v = JsonrpcRawRequest::new(1, "eth_call", &(txn, "latest")).unwrap();
+        }
+        let tr = report(&rules, &route, &mut decoys, req);
+        assert!(tr.contains("\"decomposed\":false"));
+        assert!(tr.contains("unregistered contract"));
+        assert!(!tr.contains("\"accountrelationship\""));
+    }
+
+    // block params of the sub-requests, once the block resolved to 0x10
+    fn sub_blocks(route: &JsonrpcRoute, block: &str) -> Vec<serde_json::Value> {
+        let rules = RuleRegistry::with_defaults();
+        let mut decoys = DecoyPool::new(Rng::new(7));
+        let mut ctx = RuleContext {
+            route,
+            rpc_path: "/eth",
+            client: None,
+            decoys: &mut decoys,
+            egress_ip: None,
+            ua: UPSTREAM_UA,
+        };
+        let req = balances_call_at(&[address(0xaa), address(0xbb)], block);
+        let mut sr = rules.sanitize(SanitizedRequest::new(req), &route.options.rules(), &mut ctx);
+        if sr.is_pinning() {
+            let id = match &sr.req_body {
+                Batchable::Batch(vs) => serde_json::to_value(&vs[0].id).unwrap(),
+                Batchable::Single(v) => serde_json::to_value(&v.id).unwrap(),
+            };
+            let resp =
+                serde_json::json!([{"jsonrpc": "2.0", "id": id, "result": {"number": "0x10"}}]);
+            let resp = Batchable::parse(&serde_json::to_vec(&resp).unwrap()).unwrap();
+            sr.pin_blocks(Some(resp), 0);
+        }
+        let reqs = match &sr.req_body {
+            Batchable::Batch(vs) => vs.clone(),
+            Batchable::Single(v) => vec![v.clone()],
+        };
+        reqs.iter()
+            .map(|v| {
+                let params: Vec<serde_json::Value> = serde_json::from_str(v.params.get()).unwrap();
+                params.last().cloned().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pending_calls_still_split() {
+        let route = route(RouteOptions::default());
+        for (block, sent) in [
+            ("latest", "0x10"),
+            ("safe", "0x10"),
+            ("0x20", "0x20"),
+            ("pending", "pending"),
+        ] {
+            let blocks = sub_blocks(&route, block);
+            // one query per (user, token)
+            assert_eq!(blocks.len(), 2, "{}", block);
+            assert!(blocks.iter().all(|v| v == sent), "{}: {:?}", block, blocks);
+        }
+    }
+
+    #[test]
+    fn metadata_reports_the_sent_ua() {
+        let route = route(RouteOptions::default());
+        let mut decoys = DecoyPool::new(Rng::new(7));
+        let client = crate::sanitizer::ClientMetadata {
+            ip: "10.0.0.1".into(),
+            ua: "curl/8.0".into(),
+            headers: vec!["User-Agent".into()],
+        };
+        let mut ctx = RuleContext {
+            route: &route,
+            rpc_path: "/eth",
+            client: Some(&client),
+            decoys: &mut decoys,
+            egress_ip: None,
+            ua: "picked/1.0",
+        };
+        let mut sr = SanitizedRequest::new(balances_call(&[address(0xaa)]));
+        match MetadataRule.rewrite_request(&mut sr, &mut ctx).pop() {
+            Some(Transform::Metadata { protected, .. }) => assert_eq!(protected.ua, "picked/1.0"),
+            _ => panic!("no metadata reported"),
+        }
+    }
+
+    fn sanitize(
+        rules: &RuleRegistry,
+        route: &JsonrpcRoute,
+        rpc_path: &str,
+        decoys: &mut DecoyPool,
+        req: Batchable<JsonrpcRawRequest>,
+    ) -> SanitizedRequest {
+        let mut ctx = RuleContext {
+            route,
+            rpc_path,
+            client: None,
+            decoys,
+            egress_ip: None,
+            ua: UPSTREAM_UA,
+        };
+        rules.sanitize(SanitizedRequest::new(req), &route.options.rules(), &mut ctx)
+    }
+
+    // the upstream answers the requests of `sr` with `results`, in order
+    fn answer(sr: &mut SanitizedRequest, results: Vec<serde_json::Value>) {
+        let reqs = match &sr.req_body {
+            Batchable::Single(v) => vec![v],
+            Batchable::Batch(vs) => vs.iter().collect(),
+        };
+        let rs = reqs
+            .into_iter()
+            .zip(results)
+            .map(|(v, mut r)| {
+                r["jsonrpc"] = "2.0".into();
+                r["id"] = serde_json::to_value(&v.id).unwrap();
+                r
+            })
+            .collect::<Vec<_>>();
+        let data = serde_json::to_vec(&rs).unwrap();
+        sr.collect_results(Some(Batchable::parse(&data).unwrap()));
+    }
+
+    // `eth_newFilter` of two addresses, installed as one filter each
+    fn new_filter() -> Batchable<JsonrpcRawRequest> {
+        let filter = serde_json::json!({
+            "address": [format!("{:?}", address(0xaa)), format!("{:?}", address(0xbb))],
+        });
+        Batchable::Single(JsonrpcRawRequest::new(1, "eth_newFilter", &(filter,)).unwrap())
+    }
+
+    #[test]
+    fn log_filters_stay_with_their_route() {
+        let rules = RuleRegistry::with_defaults();
+        let route = route(RouteOptions::default());
+        let mut decoys = DecoyPool::new(Rng::new(7));
+        let mut sr = sanitize(&rules, &route, "/eth", &mut decoys, new_filter());
+        assert_eq!(sr.decomposed_by(0), Some("logs"));
+        answer(
+            &mut sr,
+            vec![
+                serde_json::json!({"result": "0x1"}),
+                serde_json::json!({"result": "0x2"}),
+            ],
+        );
+        assert!(!sr.retry_failed(&rules, 2));
+        let id = match sr.rewrite_response(&rules) {
+            Batchable::Single(JsonrpcResponseRawResult::Ok(v)) => {
+                serde_json::from_raw_value::<String>(&v.result).unwrap()
+            }
+            _ => panic!("no filter id"),
+        };
+
+        let changes = || {
+            Batchable::Single(JsonrpcRawRequest::new(2, "eth_getFilterChanges", &(&id,)).unwrap())
+        };
+        let sr = sanitize(&rules, &route, "/eth", &mut decoys, changes());
+        assert_eq!(sr.decomposed_by(0), Some("logs"));
+        // forwarded as is, to an upstream that doesn't know it
+        let sr = sanitize(&rules, &route, "/bsc", &mut decoys, changes());
+        assert_eq!(sr.decomposed_by(0), None);
+    }
+
+    #[test]
+    fn failed_filter_parts_are_uninstalled() {
+        let rules = RuleRegistry::with_defaults();
+        let route = route(RouteOptions::default());
+        let mut decoys = DecoyPool::new(Rng::new(7));
+        let mut sr = sanitize(&rules, &route, "/eth", &mut decoys, new_filter());
+        let installed_at = sr.pinned_upstream(0);
+        assert!(installed_at.is_some());
+        answer(
+            &mut sr,
+            vec![
+                serde_json::json!({"result": "0x1"}),
+                serde_json::json!({"error": {"code": -32000, "message": "busy"}}),
+            ],
+        );
+
+        // not sent again, the installed part is undone
+        assert!(sr.retry_failed(&rules, 2));
+        match &sr.req_body {
+            Batchable::Batch(vs) => {
+                assert_eq!(vs.len(), 1);
+                assert_eq!(vs[0].method, "eth_uninstallFilter");
+                assert_eq!(
+                    serde_json::to_value(&vs[0].params).unwrap(),
+                    serde_json::json!(["0x1"])
+                );
+            }
+            _ => panic!("not a batch"),
+        }
+        assert_eq!(sr.pinned_upstream(0), installed_at);
+
+        answer(&mut sr, vec![serde_json::json!({"result": true})]);
+        assert!(!sr.retry_failed(&rules, 2));
+        assert!(matches!(
+            sr.rewrite_response(&rules),
+            Batchable::Single(JsonrpcResponseRawResult::Err(_))
+        ));
+    }
+}
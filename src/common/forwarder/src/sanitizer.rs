@@ -1,18 +1,134 @@
 use std::prelude::v1::*;
 
+use std::any::Any;
+use std::collections::BTreeMap;
+
 use base::time;
-use eth_types::SU256;
+use eth_types::U256;
 use jsonrpc::{
     Batchable, JsonrpcErrorObj, JsonrpcRawRequest, JsonrpcRawResponseFull, JsonrpcResponseRawResult,
 };
 use serde::Serialize;
+use serde_json::value::RawValue;
+
+use crate::block::{self, BlockParam};
+use crate::decoy::DecoyPool;
+use crate::proxy::TrustedProxies;
+use crate::rng;
+use crate::route::{self, JsonrpcRoute};
+use crate::types::BLOCK_UNRESOLVED_CODE;
+use crate::utils;
 
-use crate::{sol, utils};
+pub use crate::rules::{
+    protect_fingerprint, AbiSignatureRule, AccountLinkageRule, AccountRelationshipRule,
+    BalancesState, DecoyRule, FingerprintRule, LogFilterRule, MetadataRule, MulticallRule,
+};
 
 pub struct SanitizedRequest {
     pub original_ids: Batchable<jsonrpc::Id>,
     pub req_body: Batchable<JsonrpcRawRequest>,
     pub tr: Vec<Transform>,
+    elems: Vec<Element>,
+    slots: Vec<(usize, usize)>, // (element, sub-request) of each request in `req_body`
+    resolving: Vec<String>,     // blocks being resolved by `req_body`, keyed by resolve request
+    retries: usize,             // rounds of failed sub-requests sent again
+}
+
+// one element of the client request, and the upstream requests it expands into
+struct Element {
+    req: JsonrpcRawRequest,
+    subs: Vec<SubRequest>,
+    decomposed: Option<Decomposed>,
+    block: Option<BlockParam>, // appended to every sub-request
+    pinned: Option<U256>,
+    error: Option<JsonrpcErrorObj>,
+    results: Vec<Option<JsonrpcResponseRawResult>>, // per upstream request, kept across retries
+    group: Option<String>,                          // sent apart from requests of other groups
+    notification: bool,                             // without an id, nothing to answer
+}
+
+pub struct SubRequest {
+    pub method: String,
+    pub params: Vec<serde_json::Value>, // without the block
+    pub decoy: bool,                    // result is dropped
+    pub upstream: Option<usize>,        // upstream it must go to, e.g. where its filter lives
+}
+
+// which rule derived `subs` from `req`, and what it needs to put the
+// results back together
+struct Decomposed {
+    rule: &'static str,
+    state: Box<dyn Any + Send>,
+}
+
+// what a rule breaks one client request into
+pub struct Decomposition {
+    pub subs: Vec<SubRequest>,
+    pub block: Option<BlockParam>,  // appended to every sub-request
+    pub state: Box<dyn Any + Send>, // handed back on `rewrite_response`
+}
+
+// a decomposed request as its rule sees it when the results are back
+pub struct DecomposedView<'a> {
+    pub subs: &'a [SubRequest],
+    pub block_number: Option<U256>, // the block all sub-requests were sent with
+    pub state: &'a (dyn Any + Send),
+}
+
+impl Element {
+    fn is_decomposed(&self) -> bool {
+        self.decomposed.is_some()
+    }
+
+    fn upstream_len(&self) -> usize {
+        if self.error.is_some() {
+            0
+        } else if self.is_decomposed() {
+            self.subs.len()
+        } else {
+            1
+        }
+    }
+
+    // the concrete block all sub-requests are sent with
+    fn block_number(&self) -> Option<U256> {
+        self.pinned
+            .or_else(|| self.block.as_ref().and_then(|v| v.number()))
+    }
+
+    fn resolve_request(&self) -> Option<(String, &'static str, serde_json::Value)> {
+        if self.error.is_some() || self.pinned.is_some() {
+            return None;
+        }
+        let (method, params) = self.block.as_ref()?.resolve_request()?;
+        let key = format!("{}{}", method, params);
+        Some((key, method, params))
+    }
+
+    // an absent id means a notification, it's forwarded without an id and
+    // never answered. `"id": null` is a request like any other and gets a
+    // response
+    fn is_notification(&self) -> bool {
+        self.notification
+    }
+
+    fn build_sub(&self, idx: usize) -> Option<JsonrpcRawRequest> {
+        let sub = &self.subs[idx];
+        let mut params = sub.params.clone();
+        if let Some(block) = &self.block {
+            params.push(match self.block_number() {
+                Some(n) => block.pinned_param(&n),
+                None => serde_json::Value::String(block.describe()),
+            });
+        }
+        match JsonrpcRawRequest::new(next_id(), &sub.method, &params) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                glog::error!("build sub-request[{}] fail: {:?}", sub.method, e);
+                None
+            }
+        }
+    }
 }
 
 impl SanitizedRequest {
@@ -21,101 +137,752 @@ impl SanitizedRequest {
             Batchable::Single(v) => Batchable::Single(v.id.clone()),
             Batchable::Batch(vs) => Batchable::Batch(vs.iter().map(|v| v.id.clone()).collect()),
         };
+        let elems = match &req_body {
+            Batchable::Single(v) => vec![v.clone()],
+            Batchable::Batch(vs) => vs.clone(),
+        };
+        let elems = elems
+            .into_iter()
+            .map(|req| Element {
+                req,
+                subs: vec![],
+                decomposed: None,
+                block: None,
+                pinned: None,
+                error: None,
+                results: vec![],
+                group: None,
+                notification: false,
+            })
+            .collect();
 
-        Self {
+        let mut sr = Self {
             original_ids,
             req_body,
             tr: vec![],
+            elems,
+            slots: vec![],
+            resolving: vec![],
+            retries: 0,
+        };
+        sr.flatten();
+        sr
+    }
+
+    // like `new`, with notifications told by the raw body `req_body` is parsed
+    // from, where an absent id isn't mistaken for `"id": null`
+    pub fn from_raw(req_body: Batchable<JsonrpcRawRequest>, data: &[u8]) -> Self {
+        let mut sr = Self::new(req_body);
+        let absent = utils::absent_ids(data);
+        for (elem, absent) in sr.elems.iter_mut().zip(absent) {
+            elem.notification = absent;
         }
+        sr.flatten();
+        sr
     }
 
-    // actually we only rewrite for `account_relationship`
-    pub fn rewrite_response(
-        &self,
-        resp: Batchable<JsonrpcResponseRawResult>,
-    ) -> Batchable<JsonrpcResponseRawResult> {
-        if self.tr.iter().any(|v| v.is_account_relationship()) {
-            let single = self.rewrite_account_relationship_response(resp);
-            return Batchable::Single(single); // aligh with `resp` type
+    fn is_decomposed(&self) -> bool {
+        self.elems.iter().any(|v| v.is_decomposed())
+    }
+
+    // number of client requests
+    pub fn len(&self) -> usize {
+        self.elems.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elems.is_empty()
+    }
+
+    // the `idx`th client request, as the client sent it apart from params
+    // rewritten by rules
+    pub fn request(&self, idx: usize) -> &JsonrpcRawRequest {
+        &self.elems[idx].req
+    }
+
+    // name of the rule that decomposed the `idx`th request
+    pub fn decomposed_by(&self, idx: usize) -> Option<&'static str> {
+        self.elems[idx].decomposed.as_ref().map(|v| v.rule)
+    }
+
+    // state of the `idx`th request if it's decomposed into a `T`
+    pub fn state<T: Any>(&self, idx: usize) -> Option<&T> {
+        let decomposed = self.elems[idx].decomposed.as_ref()?;
+        decomposed.state.downcast_ref()
+    }
+
+    // sub-requests of the `idx`th request, `None` if not decomposed
+    pub fn subs_mut(&mut self, idx: usize) -> Option<&mut Vec<SubRequest>> {
+        let elem = &mut self.elems[idx];
+        if !elem.is_decomposed() {
+            return None;
         }
-        resp
+        Some(&mut elem.subs)
     }
 
-    fn rewrite_account_relationship_response(
-        &self,
-        resp: Batchable<JsonrpcResponseRawResult>,
-    ) -> JsonrpcResponseRawResult {
-        let err =
-            JsonrpcRawResponseFull::err(JsonrpcErrorObj::unknown("unknown error"), None).into();
-
-        let response = match resp {
-            Batchable::Single(_) => {
-                glog::error!("protect_account_error: remote_response not batch");
-                return err;
+    // send the `idx`th request as sub-requests, its response is rewritten by `rule`
+    pub fn decompose(&mut self, idx: usize, rule: &'static str, d: Decomposition) {
+        let elem = &mut self.elems[idx];
+        elem.subs = d.subs;
+        elem.block = d.block;
+        elem.decomposed = Some(Decomposed {
+            rule,
+            state: d.state,
+        });
+    }
+
+    // send the `idx`th request along with requests of the same `group` only
+    pub fn set_group(&mut self, idx: usize, group: String) {
+        self.elems[idx].group = Some(group);
+    }
+
+    // forward the `idx`th request with other params
+    pub fn set_params(&mut self, idx: usize, params: Box<RawValue>) {
+        self.elems[idx].req.params = params;
+    }
+
+    // whether `req_body` currently resolves blocks rather than carrying the requests
+    pub fn is_pinning(&self) -> bool {
+        !self.resolving.is_empty()
+    }
+
+    // rebuild `req_body` from elements. client ids (uuids, timestamps, session
+    // counters..) are never sent upstream, every request gets a random one, by
+    // which its response is traced back to the element
+    pub(crate) fn flatten(&mut self) {
+        // pin the blocks of decomposed elements first, so every sub-request of
+        // one element reads the same state
+        self.resolving.clear();
+        self.slots.clear();
+        let mut reqs = vec![];
+        for elem in &self.elems {
+            if let Some((key, method, params)) = elem.resolve_request() {
+                if self.resolving.contains(&key) {
+                    continue;
+                }
+                match JsonrpcRawRequest::new(next_id(), method, &params) {
+                    Ok(v) => {
+                        reqs.push(v);
+                        self.resolving.push(key);
+                    }
+                    Err(e) => glog::error!("build resolve request fail: {:?}", e),
+                }
             }
-            Batchable::Batch(rs) => {
-                let mut vs = vec![];
-                for r in rs {
-                    match r {
-                        JsonrpcResponseRawResult::Ok(v) => vs.push(v),
-                        JsonrpcResponseRawResult::Err(e) => {
-                            glog::error!(
-                                "protect_account_error: remote_response contains error {:?}",
-                                e.error
-                            );
-                            return err;
-                        }
+        }
+        if !reqs.is_empty() {
+            self.req_body = Batchable::Batch(reqs);
+            return;
+        }
+
+        for (idx, elem) in self.elems.iter_mut().enumerate() {
+            elem.results = (0..elem.upstream_len()).map(|_| None).collect();
+            if elem.error.is_some() {
+                continue;
+            }
+            if elem.is_decomposed() {
+                for sub in 0..elem.subs.len() {
+                    if let Some(v) = elem.build_sub(sub) {
+                        reqs.push(v);
+                        self.slots.push((idx, sub));
                     }
                 }
-                vs
+            } else if elem.is_notification() {
+                reqs.push(elem.req.clone());
+                self.slots.push((idx, 0));
+            } else {
+                let v = &elem.req;
+                match JsonrpcRawRequest::new(next_id(), &v.method, &v.params) {
+                    Ok(v) => {
+                        reqs.push(v);
+                        self.slots.push((idx, 0));
+                    }
+                    Err(e) => glog::error!("flatten request[{}] fail: {:?}", v.method, e),
+                }
+            }
+        }
+        self.req_body = match &self.original_ids {
+            Batchable::Single(_) if reqs.len() == 1 && !self.is_decomposed() => {
+                Batchable::Single(reqs.remove(0))
+            }
+            _ => Batchable::Batch(reqs),
+        };
+    }
+
+    // (upstream id, client id) of requests forwarded one to one
+    pub fn id_pairs(&self) -> Vec<(jsonrpc::Id, jsonrpc::Id)> {
+        let reqs = match &self.req_body {
+            Batchable::Single(v) => std::slice::from_ref(v),
+            Batchable::Batch(vs) => vs.as_slice(),
+        };
+        self.slots
+            .iter()
+            .zip(reqs)
+            .map(|((idx, _), r)| (&self.elems[*idx], r))
+            .filter(|(v, _)| !v.is_decomposed() && !v.is_notification())
+            .map(|(v, r)| (r.id.clone(), v.req.id.clone()))
+            .collect()
+    }
+
+    // whether the request at `pos` of `req_body` is a client notification
+    pub fn is_notification(&self, pos: usize) -> bool {
+        self.slots
+            .get(pos)
+            .map(|(idx, _)| self.elems[*idx].is_notification())
+            .unwrap_or(false)
+    }
+
+    // the request at `pos` of `req_body` as sent upstream. notifications go
+    // without the `id` key, so upstreams don't answer them
+    pub fn upstream_request(&self, pos: usize) -> Option<serde_json::Value> {
+        let req = match &self.req_body {
+            Batchable::Single(v) if pos == 0 => v,
+            Batchable::Single(_) => return None,
+            Batchable::Batch(vs) => vs.get(pos)?,
+        };
+        let mut v = serde_json::to_value(req).ok()?;
+        if self.is_notification(pos) {
+            if let Some(obj) = v.as_object_mut() {
+                obj.remove("id");
+            }
+        }
+        Some(v)
+    }
+
+    // all of `req_body` as sent upstream
+    pub fn upstream_body(&self) -> Vec<u8> {
+        let body = match &self.req_body {
+            Batchable::Single(_) => self.upstream_request(0).unwrap_or_default(),
+            Batchable::Batch(vs) => serde_json::Value::Array(
+                (0..vs.len())
+                    .filter_map(|idx| self.upstream_request(idx))
+                    .collect(),
+            ),
+        };
+        serde_json::to_vec(&body).unwrap()
+    }
+
+    // the upstream the request at `pos` of `req_body` must go to
+    pub fn pinned_upstream(&self, pos: usize) -> Option<usize> {
+        let (idx, sub) = self.slots.get(pos)?;
+        let elem = &self.elems[*idx];
+        match elem.is_decomposed() {
+            true => elem.subs.get(*sub)?.upstream,
+            false => None,
+        }
+    }
+
+    // indices into `req_body`, each group goes upstream as one http request.
+    // when `apart`, every sub-request of a decomposed element stands alone,
+    // requests given a group go with their group, while the rest share one
+    // group as the client sent them together. sub-requests pinned to an
+    // upstream always stand alone
+    pub fn dispatch_groups(&self, apart: bool) -> Vec<Vec<usize>> {
+        let len = match &self.req_body {
+            Batchable::Single(_) => 1,
+            Batchable::Batch(vs) => vs.len(),
+        };
+        if len == 0 {
+            return vec![];
+        }
+        if self.is_pinning() {
+            return vec![(0..len).collect()];
+        }
+
+        let mut shared = vec![];
+        let mut groups = vec![];
+        let mut keyed = Vec::<(&str, Vec<usize>)>::new();
+        for (pos, (idx, _)) in self.slots.iter().enumerate() {
+            let elem = &self.elems[*idx];
+            if self.pinned_upstream(pos).is_some() || (apart && elem.is_decomposed()) {
+                groups.push(vec![pos]);
+            } else if let Some(group) = elem.group.as_ref().filter(|_| apart) {
+                match keyed.iter_mut().find(|(k, _)| *k == group.as_str()) {
+                    Some((_, v)) => v.push(pos),
+                    None => keyed.push((group, vec![pos])),
+                }
+            } else {
+                shared.push(pos);
             }
+        }
+        groups.extend(keyed.into_iter().map(|(_, v)| v));
+        if !shared.is_empty() {
+            groups.insert(0, shared);
+        }
+        groups
+    }
+
+    // feed the response of the resolving round, `req_body` is then rebuilt
+    // with the actual requests. blocks not resolved are resolved again, at
+    // most `budget` rounds like failed sub-requests
+    pub fn pin_blocks(&mut self, resp: Option<Batchable<JsonrpcResponseRawResult>>, budget: usize) {
+        let (results, _) = match resp {
+            Some(resp) => self.index_results(resp),
+            None => (vec![], None),
         };
+        let resolving = std::mem::take(&mut self.resolving);
+        let numbers = resolving
+            .into_iter()
+            .zip(results)
+            .filter_map(|(key, r)| match r {
+                Some(JsonrpcResponseRawResult::Ok(v)) => {
+                    block::parse_block_number(&v.result).map(|n| (key, n))
+                }
+                _ => None,
+            })
+            .collect::<BTreeMap<_, _>>();
 
-        match &self.req_body {
-            Batchable::Batch(vs) => {
-                if vs.len() != response.len() {
-                    glog::error!(
-                        "protect_account_error: req.len({}) != resp.len({})",
-                        vs.len(),
-                        response.len()
-                    );
-                    err
-                } else {
-                    // get balances from batch
-                    let mut balances = vec![];
-                    for v in response {
-                        match serde_json::from_raw_value::<SU256>(&v.result) {
-                            Ok(v) => balances.push(*v),
-                            Err(_) => {
-                                glog::error!("protected_account_error: deser jsonrpc");
-                                return err;
-                            }
-                        }
-                    }
-                    // encode balances
-                    let encoded = sol::encode_uint256_array(&balances);
-                    let result = {
-                        let hex_str = String::from("0x") + &hex::encode(encoded);
-                        serde_json::to_raw_value(&hex_str).ok()
-                    };
-                    let id = match &self.original_ids {
-                        Batchable::Single(v) => Some(v.clone()),
-                        Batchable::Batch(vs) => vs.first().cloned(),
-                    };
-                    JsonrpcRawResponseFull {
-                        jsonrpc: "2.0".into(),
-                        result,
-                        error: None,
-                        id,
+        let retry = self.retries < budget;
+        let mut unresolved = 0;
+        for elem in &mut self.elems {
+            if let Some((key, _, _)) = elem.resolve_request() {
+                match numbers.get(&key) {
+                    Some(n) => elem.pinned = Some(*n),
+                    None if retry => unresolved += 1,
+                    None => {
+                        glog::error!("protect_account_error: resolve block {}", key);
+                        elem.error = Some(block_unresolved_error());
                     }
-                    .into()
                 }
             }
-            _ => {
-                glog::error!("protect_account_error: req not batch",);
-                err
+        }
+        if unresolved > 0 {
+            self.retries += 1;
+            glog::warn!(
+                "resolve {} blocks again, round {}",
+                unresolved,
+                self.retries
+            );
+        }
+        self.flatten();
+    }
+
+    // upstream results in `req_body` order, and the error of a rejected batch
+    fn index_results(
+        &self,
+        resp: Batchable<JsonrpcResponseRawResult>,
+    ) -> (
+        Vec<Option<JsonrpcResponseRawResult>>,
+        Option<JsonrpcErrorObj>,
+    ) {
+        let reqs = match &self.req_body {
+            Batchable::Single(v) => std::slice::from_ref(v),
+            Batchable::Batch(vs) => vs.as_slice(),
+        };
+        // replies to notifications are dropped as unknown
+        let mut upstream_ids = BTreeMap::<String, Vec<usize>>::new();
+        for (idx, v) in reqs.iter().enumerate() {
+            if self.is_notification(idx) {
+                continue;
+            }
+            if let Ok(id) = serde_json::to_string(&v.id) {
+                upstream_ids.entry(id).or_default().push(idx);
+            }
+        }
+        let mut results = Vec::with_capacity(reqs.len());
+        results.resize_with(reqs.len(), || None);
+        let mut batch_err = None;
+        let rs = match resp {
+            Batchable::Batch(rs) => rs,
+            // the whole batch is rejected
+            Batchable::Single(JsonrpcResponseRawResult::Err(e)) if e.id.is_none() => {
+                batch_err = Some(e.error);
+                vec![]
+            }
+            Batchable::Single(r) => vec![r],
+        };
+        for r in rs {
+            let idx = serde_json::to_string(&utils::get_response_id(&r))
+                .ok()
+                .and_then(|id| upstream_ids.get(&id))
+                .and_then(|idxs| idxs.iter().find(|idx| results[**idx].is_none()));
+            match idx {
+                Some(idx) => results[*idx] = Some(r),
+                None => glog::error!("sanitize_error: unknown response id"),
+            }
+        }
+        (results, batch_err)
+    }
+
+    // keep the upstream results of `req_body` with their elements, `None` if
+    // nothing could be parsed
+    pub fn collect_results(&mut self, resp: Option<Batchable<JsonrpcResponseRawResult>>) {
+        let (mut results, batch_err) = match resp {
+            Some(resp) => self.index_results(resp),
+            None => (vec![], None),
+        };
+        for (pos, (idx, sub)) in self.slots.iter().enumerate() {
+            let r = results.get_mut(pos).and_then(|v| v.take()).or_else(|| {
+                // a rejected batch fails every request in it
+                let e = batch_err.clone()?;
+                Some(JsonrpcRawResponseFull::err(e, None).into())
+            });
+            if let Some(slot) = self.elems[*idx].results.get_mut(*sub) {
+                *slot = r;
+            }
+        }
+    }
+
+    // put failed sub-requests into `req_body` again, at most `budget` rounds.
+    // the ones that can't be sent again are undone instead, see
+    // `SanitizerRule::rollback`. `false` if there's nothing to send
+    pub fn retry_failed(&mut self, rules: &RuleRegistry, budget: usize) -> bool {
+        let mut failed = vec![];
+        let mut undone = vec![];
+        for (idx, elem) in self.elems.iter().enumerate() {
+            let rule = match &elem.decomposed {
+                Some(d) if elem.error.is_none() => match rules.get(d.rule) {
+                    Some(v) => v,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let mut subs = vec![];
+            for (sub, (sub_req, r)) in elem.subs.iter().zip(&elem.results).enumerate() {
+                if sub_req.decoy {
+                    // dropped anyway
+                    continue;
+                }
+                let ok = match r {
+                    Some(r) => !rule.sub_failed(sub_req, r),
+                    None => false,
+                };
+                if !ok {
+                    subs.push(sub);
+                }
+            }
+            if subs.is_empty() {
+                continue;
+            }
+            // a failed sub-request may still have taken effect upstream, the
+            // element is answered with the error instead
+            if subs
+                .iter()
+                .any(|v| !route::is_idempotent(&elem.subs[*v].method))
+            {
+                let undo = rule.rollback(&elem.subs, &elem.results);
+                if !undo.is_empty() {
+                    undone.push((idx, undo));
+                }
+                continue;
+            }
+            failed.extend(subs.into_iter().map(|sub| (idx, sub)));
+        }
+        if self.retries >= budget {
+            failed.clear();
+        }
+        if failed.is_empty() && undone.is_empty() {
+            return false;
+        }
+
+        self.slots.clear();
+        let mut reqs = vec![];
+        for (idx, undo) in undone {
+            glog::warn!("undo {} sub-requests of a failed request", undo.len());
+            // the results of the undoing ones are dropped
+            let elem = &mut self.elems[idx];
+            elem.error = Some(sub_request_error());
+            elem.results = undo.iter().map(|_| None).collect();
+            elem.subs = undo;
+            for sub in 0..elem.subs.len() {
+                if let Some(v) = elem.build_sub(sub) {
+                    reqs.push(v);
+                    self.slots.push((idx, sub));
+                }
             }
         }
+        if !failed.is_empty() {
+            self.retries += 1;
+            glog::warn!(
+                "retry {} failed sub-requests, round {}",
+                failed.len(),
+                self.retries
+            );
+        }
+        for (idx, sub) in failed {
+            if let Some(v) = self.elems[idx].build_sub(sub) {
+                reqs.push(v);
+                self.slots.push((idx, sub));
+            }
+        }
+        self.req_body = Batchable::Batch(reqs);
+        true
+    }
+
+    // rounds of retries so far
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    // restore client ids, and put decomposed results back together
+    pub fn rewrite_response(
+        &mut self,
+        rules: &RuleRegistry,
+    ) -> Batchable<JsonrpcResponseRawResult> {
+        let mut out = vec![];
+        for elem in &mut self.elems {
+            let rs = std::mem::take(&mut elem.results);
+            if elem.is_notification() {
+                // nothing to answer, whatever the upstream replied
+                continue;
+            }
+            let id = elem.req.id.clone();
+            let rewritten = match &elem.error {
+                Some(e) => JsonrpcRawResponseFull::err(e.clone(), Some(id)).into(),
+                None => rewrite_element_response(elem, rs, id, rules),
+            };
+            out.push(rewritten);
+        }
+
+        match &self.original_ids {
+            Batchable::Single(_) if out.len() == 1 => Batchable::Single(out.remove(0)),
+            _ => Batchable::Batch(out),
+        }
+    }
+}
+
+// upstream id -> client id of requests in flight over one ws connection
+#[derive(Default)]
+pub struct WsIdMap(BTreeMap<String, jsonrpc::Id>);
+
+impl WsIdMap {
+    pub fn record(&mut self, sr: &SanitizedRequest) {
+        for (upstream, client) in sr.id_pairs() {
+            if let Ok(id) = serde_json::to_string(&upstream) {
+                self.0.insert(id, client);
+            }
+        }
+    }
+
+    // put client ids back into upstream responses, subscription notifications
+    // aren't responses and pass through
+    pub fn restore(&mut self, data: Vec<u8>) -> Vec<u8> {
+        let resp = match Batchable::<JsonrpcResponseRawResult>::parse(&data) {
+            Ok(v) => v,
+            Err(_) => return data,
+        };
+        let mut restore = |r: JsonrpcResponseRawResult| {
+            let mut r = r.to_full();
+            let client =
+                r.id.as_ref()
+                    .and_then(|id| serde_json::to_string(id).ok())
+                    .and_then(|id| self.0.remove(&id));
+            if client.is_some() {
+                r.id = client;
+            }
+            r
+        };
+        let resp = match resp {
+            Batchable::Single(r) => Batchable::Single(restore(r)),
+            Batchable::Batch(rs) => Batchable::Batch(rs.into_iter().map(&mut restore).collect()),
+        };
+        serde_json::to_vec(&resp).unwrap_or(data)
+    }
+}
+
+// what the client gets for a sub-request that still fails after retries,
+// nothing of the upstream error is passed on
+pub fn sub_request_error() -> JsonrpcErrorObj {
+    JsonrpcErrorObj::error(-32603, "Internal error: upstream request failed".into())
+}
+
+// the block of a broken down call couldn't be pinned
+pub fn block_unresolved_error() -> JsonrpcErrorObj {
+    JsonrpcErrorObj::error(BLOCK_UNRESOLVED_CODE, "block not resolved upstream".into())
+}
+
+// drawn on its own, within the integers json numbers hold exactly
+fn next_id() -> u64 {
+    rng::entropy_u64() >> 11
+}
+
+fn rewrite_element_response(
+    elem: &Element,
+    mut rs: Vec<Option<JsonrpcResponseRawResult>>,
+    id: jsonrpc::Id,
+    rules: &RuleRegistry,
+) -> JsonrpcResponseRawResult {
+    let rewritten = match &elem.decomposed {
+        None => match rs.pop().flatten() {
+            Some(JsonrpcResponseRawResult::Ok(mut v)) => {
+                v.id = id;
+                return JsonrpcResponseRawResult::Ok(v);
+            }
+            Some(JsonrpcResponseRawResult::Err(mut v)) => {
+                v.id = Some(id);
+                return JsonrpcResponseRawResult::Err(v);
+            }
+            None => Err(sub_request_error()),
+        },
+        Some(decomposed) => match rules.get(decomposed.rule) {
+            Some(rule) => {
+                let view = DecomposedView {
+                    subs: &elem.subs,
+                    block_number: elem.block_number(),
+                    state: &*decomposed.state,
+                };
+                rule.rewrite_response(&view, rs)
+            }
+            None => {
+                glog::error!("sanitize_error: unknown rule {}", decomposed.rule);
+                Err(JsonrpcErrorObj::unknown("unknown error"))
+            }
+        },
+    };
+    match rewritten {
+        Ok(result) => JsonrpcRawResponseFull {
+            jsonrpc: "2.0".into(),
+            result: Some(result),
+            error: None,
+            id: Some(id),
+        }
+        .into(),
+        Err(err) => JsonrpcRawResponseFull::err(err, Some(id)).into(),
+    }
+}
+
+// where a client request comes from, as the forwarder sees it
+pub struct ClientMetadata {
+    pub ip: String,
+    pub ua: String,
+    pub headers: Vec<String>, // names, as received
+}
+
+impl ClientMetadata {
+    pub fn from_http(
+        ctx: &net_http::HttpServerContext,
+        req: &mut net_http::HttpRequestReader,
+        proxies: &TrustedProxies,
+    ) -> Self {
+        Self {
+            ip: utils::get_client_ip(req, ctx.peer_addr, proxies),
+            ua: utils::get_cilent_ua(req),
+            headers: utils::get_header_names(req),
+        }
+    }
+}
+
+// what a rule gets to know about the request besides its body
+pub struct RuleContext<'a> {
+    pub route: &'a JsonrpcRoute,
+    pub rpc_path: &'a str,                  // of the route
+    pub client: Option<&'a ClientMetadata>, // `None` if not over http
+    pub decoys: &'a mut DecoyPool,
+    pub egress_ip: Option<&'a str>, // where upstreams see us, `None` if not known yet
+    pub ua: &'a str,                // User-Agent sent upstream, picked once per request
+}
+
+// one protection applied to client requests. a rule may rewrite params of
+// requests in place, or decompose requests into sub-requests whose results
+// it puts back together in `rewrite_response`
+pub trait SanitizerRule {
+    // unique, referred by the `rules` of routes
+    fn name(&self) -> &'static str;
+
+    // rewrite `sr`, and report what was changed
+    fn rewrite_request(&self, sr: &mut SanitizedRequest, ctx: &mut RuleContext) -> Vec<Transform>;
+
+    // whether the result of one sub-request is no use, it's then sent again
+    // while the route's retry budget lasts
+    fn sub_failed(&self, _sub: &SubRequest, r: &JsonrpcResponseRawResult) -> bool {
+        matches!(r, JsonrpcResponseRawResult::Err(_))
+    }
+
+    // sub-requests undoing what the ones in `subs` that went through did,
+    // sent once a failed one can't be sent again. `rs` are their results
+    fn rollback(
+        &self,
+        _subs: &[SubRequest],
+        _rs: &[Option<JsonrpcResponseRawResult>],
+    ) -> Vec<SubRequest> {
+        vec![]
+    }
+
+    // result of a request decomposed by this rule, from the results of its
+    // sub-requests in order
+    fn rewrite_response(
+        &self,
+        _elem: &DecomposedView,
+        _rs: Vec<Option<JsonrpcResponseRawResult>>,
+    ) -> Result<Box<RawValue>, JsonrpcErrorObj> {
+        Err(JsonrpcErrorObj::unknown("unknown error"))
+    }
+}
+
+// `data` as the hex string result of an `eth_call`
+pub fn hex_result(data: Vec<u8>) -> Result<Box<RawValue>, JsonrpcErrorObj> {
+    let hex_str = String::from("0x") + &hex::encode(data);
+    serde_json::to_raw_value(&hex_str).map_err(|_| JsonrpcErrorObj::unknown("unknown error"))
+}
+
+// rules run on routes without a `rules` list, in order
+pub const DEFAULT_RULES: [&str; 8] = [
+    "abi",
+    "account_relationship",
+    "multicall",
+    "decoy",
+    "account_linkage",
+    "fingerprint",
+    "logs",
+    "metadata",
+];
+
+#[derive(Default)]
+pub struct RuleRegistry {
+    rules: Vec<Box<dyn SanitizerRule>>,
+}
+
+impl RuleRegistry {
+    pub fn new() -> Self {
+        Self { rules: vec![] }
+    }
+
+    // the built-in rules
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new();
+        registry.register(Box::new(AbiSignatureRule));
+        registry.register(Box::new(AccountRelationshipRule));
+        registry.register(Box::new(MulticallRule));
+        registry.register(Box::new(DecoyRule));
+        registry.register(Box::new(AccountLinkageRule));
+        registry.register(Box::new(FingerprintRule));
+        registry.register(Box::new(LogFilterRule::new()));
+        registry.register(Box::new(MetadataRule));
+        registry
+    }
+
+    // a rule of the same name is replaced
+    pub fn register(&mut self, rule: Box<dyn SanitizerRule>) {
+        self.rules.retain(|v| v.name() != rule.name());
+        self.rules.push(rule);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn SanitizerRule> {
+        self.rules
+            .iter()
+            .find(|v| v.name() == name)
+            .map(|v| v.as_ref())
+    }
+
+    // apply rules by the given names in order
+    pub fn sanitize<S: AsRef<str>>(
+        &self,
+        mut sr: SanitizedRequest,
+        order: &[S],
+        ctx: &mut RuleContext,
+    ) -> SanitizedRequest {
+        for name in order {
+            let rule = match self.get(name.as_ref()) {
+                Some(v) => v,
+                None => {
+                    glog::warn!("sanitize: unknown rule {}", name.as_ref());
+                    continue;
+                }
+            };
+            let tr = rule.rewrite_request(&mut sr, ctx);
+            sr.tr.extend(tr);
+            sr.flatten();
+        }
+        sr
     }
 }
 
@@ -132,9 +899,41 @@ pub enum Transform {
         protected: Metadata,
         unprotected: Metadata,
     },
+    #[serde(rename = "timing")]
+    Timing {
+        protected: Timing,
+        unprotected: Timing,
+    },
+    #[serde(rename = "normalization")]
+    Normalization {
+        protected: Vec<Normalization>,
+        unprotected: Vec<Normalization>,
+    },
+    #[serde(rename = "decoy")]
+    Decoy {
+        protected: Vec<AccountRelationship>, // queries sent along, results dropped
+        unprotected: Vec<AccountRelationship>,
+    },
+    #[serde(rename = "contractDetection")]
+    ContractDetection { decisions: Vec<ContractDetection> },
+    // upstreams of the route see requests in the clear
+    #[serde(rename = "plaintextUpstream")]
+    PlaintextUpstream { upstreams: usize, time: String },
 }
 
 impl Transform {
+    // `delays` of each upstream request, which would otherwise go at once
+    pub fn timing(delays: Vec<u64>) -> Self {
+        let now = time::Date::from(time::now()).to_string();
+        Transform::Timing {
+            unprotected: Timing {
+                delays: vec![0; delays.len()],
+                time: now.clone(),
+            },
+            protected: Timing { delays, time: now },
+        }
+    }
+
     pub fn is_account_relationship(&self) -> bool {
         match self {
             Transform::AccountRelationship {
@@ -164,129 +963,295 @@ pub struct AccountRelationship {
     pub time: String, // utc date
 }
 
+#[derive(Serialize)]
+pub struct Timing {
+    pub delays: Vec<u64>, // ms, per upstream request
+    pub time: String,     // utc date
+}
+
+#[derive(Serialize)]
+pub struct Normalization {
+    pub method: String,
+    pub params: String,
+    pub rules: Vec<&'static str>,
+}
+
+// whether a call to a known selector was broken down
+#[derive(Serialize)]
+pub struct ContractDetection {
+    pub contract: String, // kind of contract the selector belongs to, or the abi rule
+    pub to: String,
+    pub selector: String,
+    pub decomposed: bool,
+    pub reason: &'static str, // empty if decomposed
+    pub time: String,         // utc date
+}
+
 #[derive(Serialize)]
 pub struct Metadata {
     pub ip: String,
     pub ua: String,
-    pub time: String, // utc date
+    pub headers: Vec<String>, // names
+    pub removed: Vec<String>, // names of client headers not passed on
+    pub time: String,         // utc date
 }
 
-pub fn protect_account_relationship(mut sr: SanitizedRequest) -> SanitizedRequest {
-    macro_rules! decode_fail {
-        ($msg:expr) => {{
-            glog::warn!("protect_account_relationship abort: {}", $msg);
-            return sr;
-        }};
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let req = match &sr.req_body {
-        Batchable::Single(v) => v,
-        _ => return sr,
-    };
+    fn parse(data: &str) -> SanitizedRequest {
+        let req_body = Batchable::parse(data.as_bytes()).unwrap();
+        SanitizedRequest::from_raw(req_body, data.as_bytes())
+    }
 
-    if req.method != "eth_call" {
-        return sr;
+    fn upstream_ids(sr: &SanitizedRequest) -> Vec<serde_json::Value> {
+        let reqs = match &sr.req_body {
+            Batchable::Single(v) => std::slice::from_ref(v),
+            Batchable::Batch(vs) => vs.as_slice(),
+        };
+        reqs.iter()
+            .map(|v| serde_json::to_value(&v.id).unwrap())
+            .collect()
     }
 
-    if let Some(calldata) = utils::get_eth_call_data_from_jsonrpc(&req) {
-        // 0xf0002ea9 is the 4 byte signature of balances(address[],address[])
-        // https://www.4byte.directory/signatures/?bytes4_signature=0xf0002ea9
-        let sig = hex::decode("f0002ea9").unwrap();
-        let data = calldata.as_bytes();
-        if !sol::func_sig_matches(data, &sig) {
-            return sr;
-        }
+    // the upstream answers `ids` with their position as result
+    fn answer(sr: &mut SanitizedRequest, ids: &[serde_json::Value]) {
+        let rs = ids
+            .iter()
+            .enumerate()
+            .map(|(idx, id)| serde_json::json!({"jsonrpc": "2.0", "id": id, "result": idx}))
+            .collect::<Vec<_>>();
+        let data = serde_json::to_vec(&rs).unwrap();
+        sr.collect_results(Some(Batchable::parse(&data).unwrap()));
+    }
 
-        let data = &data[4..]; // skip sig
-        let users = if let Some(v) = sol::decode_uint256(data) {
-            let offset = v.as_usize();
-            sol::decode_address_array(&data[offset..])
-        } else {
-            decode_fail!("users");
+    fn response_ids(resp: &Batchable<JsonrpcResponseRawResult>) -> Vec<serde_json::Value> {
+        let rs = match resp {
+            Batchable::Single(v) => std::slice::from_ref(v),
+            Batchable::Batch(vs) => vs.as_slice(),
         };
+        rs.iter()
+            .map(|r| match r {
+                JsonrpcResponseRawResult::Ok(v) => serde_json::to_value(&v.id).unwrap(),
+                JsonrpcResponseRawResult::Err(v) => serde_json::to_value(&v.id).unwrap(),
+            })
+            .collect()
+    }
 
-        let tokens = if let Some(v) = sol::decode_uint256(&data[32..]) {
-            let offset = v.as_usize();
-            sol::decode_address_array(&data[offset..])
-        } else {
-            decode_fail!("tokens");
+    #[test]
+    fn batch_ids_are_restored() {
+        let mut sr = parse(
+            r#"[
+                {"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []},
+                {"jsonrpc": "2.0", "id": "a", "method": "net_version", "params": []}
+            ]"#,
+        );
+        let ids = upstream_ids(&sr);
+        assert_eq!(ids.len(), 2);
+        assert!(ids.iter().all(|v| v.is_u64()));
+        assert!(!ids.contains(&serde_json::json!(1)));
+
+        // answered out of order
+        answer(&mut sr, &[ids[1].clone(), ids[0].clone()]);
+        let resp = sr.rewrite_response(&RuleRegistry::new());
+        assert!(matches!(resp, Batchable::Batch(_)));
+        assert_eq!(
+            response_ids(&resp),
+            vec![serde_json::json!(1), serde_json::json!("a")]
+        );
+    }
+
+    #[test]
+    fn string_id_is_restored() {
+        let mut sr = parse(r#"{"jsonrpc": "2.0", "id": "abc", "method": "eth_chainId"}"#);
+        let ids = upstream_ids(&sr);
+        assert_eq!(ids.len(), 1);
+        assert!(ids[0].is_u64());
+
+        answer(&mut sr, &ids);
+        let resp = sr.rewrite_response(&RuleRegistry::new());
+        assert!(matches!(resp, Batchable::Single(_)));
+        assert_eq!(response_ids(&resp), vec![serde_json::json!("abc")]);
+    }
+
+    #[test]
+    fn null_id_is_answered() {
+        let mut sr = parse(r#"{"jsonrpc": "2.0", "id": null, "method": "eth_chainId"}"#);
+        let ids = upstream_ids(&sr);
+        assert!(ids[0].is_u64());
+        assert_eq!(sr.id_pairs().len(), 1);
+
+        answer(&mut sr, &ids);
+        let resp = sr.rewrite_response(&RuleRegistry::new());
+        assert_eq!(response_ids(&resp), vec![serde_json::Value::Null]);
+    }
+
+    #[test]
+    fn notification_is_not_answered() {
+        let mut sr = parse(
+            r#"[
+                {"jsonrpc": "2.0", "method": "eth_chainId"},
+                {"jsonrpc": "2.0", "id": 7, "method": "net_version"}
+            ]"#,
+        );
+        let ids = upstream_ids(&sr);
+        assert_eq!(ids.len(), 2);
+        assert!(ids[1].is_u64());
+        assert_eq!(sr.id_pairs().len(), 1);
+        let sent: Vec<serde_json::Value> = serde_json::from_slice(&sr.upstream_body()).unwrap();
+        assert!(sent[0].get("id").is_none());
+        assert_eq!(sent[1]["id"], ids[1]);
+
+        // an upstream answering the notification anyway
+        answer(&mut sr, &ids);
+        let resp = sr.rewrite_response(&RuleRegistry::new());
+        assert_eq!(response_ids(&resp), vec![serde_json::json!(7)]);
+    }
+
+    // an `eth_call` broken down into two balance queries at `latest`
+    fn pinning() -> SanitizedRequest {
+        let mut sr = parse(r#"{"jsonrpc": "2.0", "id": 9, "method": "eth_call", "params": [{}]}"#);
+        let subs = (0..2)
+            .map(|v| SubRequest {
+                method: "eth_getBalance".into(),
+                params: vec![serde_json::json!(format!("0x{:040x}", v))],
+                decoy: false,
+                upstream: None,
+            })
+            .collect();
+        let d = Decomposition {
+            subs,
+            block: Some(BlockParam::Tag("latest".into())),
+            state: Box::new(()),
         };
+        sr.decompose(0, "test", d);
+        sr.flatten();
+        sr
+    }
+
+    fn resolve_response(
+        sr: &SanitizedRequest,
+        r: serde_json::Value,
+    ) -> Batchable<JsonrpcResponseRawResult> {
+        let mut r = r;
+        r["jsonrpc"] = "2.0".into();
+        r["id"] = upstream_ids(sr)[0].clone();
+        Batchable::parse(&serde_json::to_vec(&vec![r]).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn blocks_pinned_once_resolved() {
+        let mut sr = pinning();
+        assert!(sr.is_pinning());
+        assert_eq!(upstream_ids(&sr).len(), 1);
 
-        // native token address only
-        if tokens.first() != Some(&eth_types::H160::zero()) {
-            decode_fail!("contains non-native-token address");
+        let resp = resolve_response(&sr, serde_json::json!({"result": {"number": "0x10"}}));
+        sr.pin_blocks(Some(resp), 1);
+        assert!(!sr.is_pinning());
+        match &sr.req_body {
+            Batchable::Batch(vs) => {
+                assert_eq!(vs.len(), 2);
+                assert!(vs.iter().all(|v| v.params.get().ends_with(r#","0x10"]"#)));
+            }
+            _ => panic!("not a batch"),
         }
+    }
 
-        let accts = users
-            .into_iter()
-            .map(|v| format!("{:?}", v))
+    #[test]
+    fn blocks_resolved_again_then_failed() {
+        let mut sr = pinning();
+        let first = upstream_ids(&sr);
+        let resp = resolve_response(
+            &sr,
+            serde_json::json!({"error": {"code": -32000, "message": "busy"}}),
+        );
+        sr.pin_blocks(Some(resp), 1);
+        // one more round, under a new id
+        assert!(sr.is_pinning());
+        assert_eq!(sr.retries(), 1);
+        assert_ne!(upstream_ids(&sr), first);
+
+        sr.pin_blocks(None, 1);
+        assert!(!sr.is_pinning());
+        assert!(upstream_ids(&sr).is_empty());
+        match sr.rewrite_response(&RuleRegistry::new()) {
+            Batchable::Single(JsonrpcResponseRawResult::Err(v)) => {
+                assert_eq!(serde_json::to_value(&v.id).unwrap(), serde_json::json!(9));
+                let err = serde_json::to_value(&v.error).unwrap();
+                assert_eq!(err["code"], serde_json::json!(BLOCK_UNRESOLVED_CODE));
+            }
+            _ => panic!("not an error"),
+        }
+    }
+
+    #[test]
+    fn upstream_ids_are_distinct() {
+        let reqs = (0..30)
+            .map(|_| serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"}))
             .collect::<Vec<_>>();
-        let new_req_body = {
-            let reqs = accts
-                .iter()
-                .enumerate()
-                .map(|(id, v)| {
-                    let params = serde_json::json!([v, "latest"]);
-                    JsonrpcRawRequest::new(id as u64, "eth_getBalance", &params).unwrap()
-                })
-                .collect::<Vec<_>>();
-            Batchable::Batch(reqs)
-        };
-        let now = time::Date::from(time::now()).to_string();
-        let tr = Transform::AccountRelationship {
-            protected: accts
-                .iter()
-                .map(|v| AccountRelationship {
-                    accounts: vec![v.clone()],
-                    method: "eth_getBalance",
-                    params: vec![],
-                    time: now.clone(),
-                })
-                .collect(),
-            unprotected: AccountRelationship {
-                accounts: accts,
-                method: "eth_call",
-                params: vec!["0xf0002ea9".into(), "latest".into()],
-                time: now.clone(),
-            },
-        };
+        let sr = parse(&serde_json::to_string(&reqs).unwrap());
+        let mut ids = upstream_ids(&sr)
+            .iter()
+            .map(|v| v.as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert!(ids.iter().all(|v| *v < 1 << 53));
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 30);
+    }
+
+    struct TestRule;
+
+    impl SanitizerRule for TestRule {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn rewrite_request(&self, _: &mut SanitizedRequest, _: &mut RuleContext) -> Vec<Transform> {
+            vec![]
+        }
+    }
 
-        sr.tr.push(tr);
-        return SanitizedRequest {
-            original_ids: sr.original_ids,
-            req_body: new_req_body,
-            tr: sr.tr,
+    // an `eth_call` broken down into two `method` sub-requests, both failed
+    fn failed_subs(method: &str) -> SanitizedRequest {
+        let mut sr = parse(r#"{"jsonrpc": "2.0", "id": 9, "method": "eth_call", "params": [{}]}"#);
+        let subs = (0..2)
+            .map(|_| SubRequest {
+                method: method.into(),
+                params: vec![],
+                decoy: false,
+                upstream: None,
+            })
+            .collect();
+        let d = Decomposition {
+            subs,
+            block: None,
+            state: Box::new(()),
         };
+        sr.decompose(0, "test", d);
+        sr.flatten();
+        let rs = upstream_ids(&sr)
+            .into_iter()
+            .map(|id| serde_json::json!({"jsonrpc": "2.0", "id": id, "error": {"code": -32000, "message": "busy"}}))
+            .collect::<Vec<_>>();
+        sr.collect_results(Some(
+            Batchable::parse(&serde_json::to_vec(&rs).unwrap()).unwrap(),
+        ));
+        sr
     }
-    return sr;
-}
 
-pub fn protect_metadata(
-    sr: SanitizedRequest,
-    ctx: &net_http::HttpServerContext,
-    req: &mut net_http::HttpRequestReader,
-) -> SanitizedRequest {
-    let SanitizedRequest {
-        original_ids,
-        req_body,
-        mut tr,
-    } = sr;
-    let now = time::Date::from(time::now()).to_string();
-    tr.push(Transform::Metadata {
-        protected: Metadata {
-            ip: utils::get_host_ip(req),
-            ua: "1rpc-demo/0.1".into(),
-            time: now.clone(),
-        },
-        unprotected: Metadata {
-            ip: utils::get_client_ip(req, ctx.peer_addr),
-            ua: utils::get_cilent_ua(req),
-            time: now.clone(),
-        },
-    });
-    SanitizedRequest {
-        original_ids,
-        req_body,
-        tr,
+    #[test]
+    fn only_idempotent_subs_are_retried() {
+        let mut rules = RuleRegistry::new();
+        rules.register(Box::new(TestRule));
+
+        let mut sr = failed_subs("eth_getBalance");
+        assert!(sr.retry_failed(&rules, 1));
+        assert_eq!(upstream_ids(&sr).len(), 2);
+
+        let mut sr = failed_subs("eth_newFilter");
+        assert!(!sr.retry_failed(&rules, 1));
+        assert_eq!(sr.retries(), 0);
     }
 }
@@ -1,44 +1,789 @@
 use eth_types::{H160, U256};
 use std::prelude::v1::*;
 
+// Solidity ABI codec
+// https://docs.soliditylang.org/en/latest/abi-spec.html
+//
+// all offsets and lengths come from untrusted calldata, so every access is
+// bounds-checked and reported as `SolError` instead of panicking
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SolError {
+    ShortData { offset: usize, len: usize },
+    Overflow,
+    InvalidType(String),
+    InvalidValue(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SolType {
+    Uint(usize),
+    Int(usize),
+    Address,
+    Bool,
+    FixedBytes(usize),
+    Bytes,
+    String,
+    Array(Box<SolType>),
+    FixedArray(Box<SolType>, usize),
+    Tuple(Vec<SolType>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SolValue {
+    Uint(U256),
+    Int(U256), // two's complement
+    Address(H160),
+    Bool(bool),
+    FixedBytes(Vec<u8>),
+    Bytes(Vec<u8>),
+    String(String),
+    Array(Vec<SolValue>),
+    FixedArray(Vec<SolValue>),
+    Tuple(Vec<SolValue>),
+}
+
+const WORD: usize = 32;
+
+impl SolType {
+    // parse canonical type strings, e.g. "address[]", "(address,bool,bytes)[]", "uint256[2][]"
+    pub fn parse(s: &str) -> Result<Self, SolError> {
+        let s = s.trim();
+        let invalid = || SolError::InvalidType(s.to_owned());
+
+        if s.ends_with(']') {
+            let open = s.rfind('[').ok_or_else(invalid)?;
+            let inner = Self::parse(&s[..open])?;
+            let size = &s[open + 1..s.len() - 1];
+            return if size.is_empty() {
+                Ok(SolType::Array(Box::new(inner)))
+            } else {
+                let n = size.parse::<usize>().map_err(|_| invalid())?;
+                Ok(SolType::FixedArray(Box::new(inner), n))
+            };
+        }
+
+        if s.starts_with('(') {
+            if !s.ends_with(')') {
+                return Err(invalid());
+            }
+            let inner = &s[1..s.len() - 1];
+            let mut tys = vec![];
+            for v in split_top_level(inner).ok_or_else(invalid)? {
+                tys.push(Self::parse(v)?);
+            }
+            return Ok(SolType::Tuple(tys));
+        }
+
+        let bits = |prefix: &str| -> Result<usize, SolError> {
+            let n = &s[prefix.len()..];
+            if n.is_empty() {
+                return Ok(256);
+            }
+            match n.parse::<usize>() {
+                Ok(v) if v > 0 && v <= 256 && v % 8 == 0 => Ok(v),
+                _ => Err(invalid()),
+            }
+        };
+        match s {
+            "address" => Ok(SolType::Address),
+            "bool" => Ok(SolType::Bool),
+            "bytes" => Ok(SolType::Bytes),
+            "string" => Ok(SolType::String),
+            _ if s.starts_with("uint") => Ok(SolType::Uint(bits("uint")?)),
+            _ if s.starts_with("int") => Ok(SolType::Int(bits("int")?)),
+            _ if s.starts_with("bytes") => match s["bytes".len()..].parse::<usize>() {
+                Ok(v) if v > 0 && v <= 32 => Ok(SolType::FixedBytes(v)),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn is_dynamic(&self) -> bool {
+        match self {
+            SolType::Bytes | SolType::String | SolType::Array(_) => true,
+            SolType::FixedArray(ty, _) => ty.is_dynamic(),
+            SolType::Tuple(tys) => tys.iter().any(|v| v.is_dynamic()),
+            _ => false,
+        }
+    }
+
+    // bytes taken in the head of the enclosing tuple
+    fn head_size(&self) -> Option<usize> {
+        if self.is_dynamic() {
+            return Some(WORD);
+        }
+        match self {
+            SolType::FixedArray(ty, n) => ty.head_size()?.checked_mul(*n),
+            SolType::Tuple(tys) => tys
+                .iter()
+                .try_fold(0usize, |acc, v| acc.checked_add(v.head_size()?)),
+            _ => Some(WORD),
+        }
+    }
+}
+
+// split "a,(b,c),d[]" at top-level commas
+fn split_top_level(s: &str) -> Option<Vec<&str>> {
+    let mut ret = vec![];
+    if s.trim().is_empty() {
+        return Some(ret);
+    }
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.checked_sub(1)?,
+            ',' if depth == 0 => {
+                ret.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return None;
+    }
+    ret.push(&s[start..]);
+    Some(ret)
+}
+
+impl SolValue {
+    pub fn is_dynamic(&self) -> bool {
+        match self {
+            SolValue::Bytes(_) | SolValue::String(_) | SolValue::Array(_) => true,
+            SolValue::FixedArray(vs) | SolValue::Tuple(vs) => vs.iter().any(|v| v.is_dynamic()),
+            _ => false,
+        }
+    }
+
+    pub fn as_address(&self) -> Option<&H160> {
+        match self {
+            SolValue::Address(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_uint(&self) -> Option<&U256> {
+        match self {
+            SolValue::Uint(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            SolValue::Bool(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            SolValue::Bytes(v) | SolValue::FixedBytes(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[SolValue]> {
+        match self {
+            SolValue::Array(vs) | SolValue::FixedArray(vs) | SolValue::Tuple(vs) => Some(vs),
+            _ => None,
+        }
+    }
+}
+
 pub fn func_sig_matches(data: &[u8], sig: &[u8]) -> bool {
-    sig == &data[..4]
+    data.get(..4) == Some(sig)
 }
 
-// `data` offseted
-pub fn decode_uint256(data: &[u8]) -> Option<U256> {
-    let mut it = data.chunks(32);
-    it.next().map(|v| U256::from_big_endian(v))
+// (selector, args)
+pub fn split_selector(data: &[u8]) -> Result<(&[u8], &[u8]), SolError> {
+    if data.len() < 4 {
+        return Err(SolError::ShortData { offset: 0, len: 4 });
+    }
+    Ok(data.split_at(4))
 }
 
-// `data` offseted
-pub fn decode_address_array(data: &[u8]) -> Vec<H160> {
-    let mut it = data.chunks(32);
-    let len = match it.next() {
-        Some(v) => U256::from_big_endian(v).as_usize(),
-        _ => return vec![],
+pub fn decode(tys: &[SolType], data: &[u8]) -> Result<Vec<SolValue>, SolError> {
+    let mut decoder = Decoder {
+        data,
+        // a well-formed encoding never holds more values than words, offsets
+        // pointing at the same region again would otherwise blow up the work
+        budget: data.len() / WORD * 2 + 1,
     };
-    it.take(len).map(|v| H160::from_slice(&v[12..])).collect()
+    decoder.tuple(tys, 0)
 }
 
-pub fn encode_uint256_array(vs: &Vec<U256>) -> Vec<u8> {
-    let mut ret = Vec::with_capacity(32 * (2 + vs.len()));
-    let mut buf = [0; 32];
+pub fn encode(vs: &[SolValue]) -> Vec<u8> {
+    encode_tuple(vs)
+}
+
+fn to_usize(v: &U256) -> Result<usize, SolError> {
+    if *v > U256::from(u32::MAX) {
+        return Err(SolError::Overflow);
+    }
+    Ok(v.as_usize())
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], SolError> {
+    let end = offset.checked_add(len).ok_or(SolError::Overflow)?;
+    data.get(offset..end)
+        .ok_or(SolError::ShortData { offset, len })
+}
+
+fn word(data: &[u8], offset: usize) -> Result<&[u8], SolError> {
+    slice(data, offset, WORD)
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    budget: usize,
+}
 
-    // offset
-    let offset = U256::from(32);
-    offset.to_big_endian(&mut buf);
-    ret.extend_from_slice(&buf);
+impl<'a> Decoder<'a> {
+    fn word(&self, offset: usize) -> Result<&'a [u8], SolError> {
+        word(self.data, offset)
+    }
 
-    // len
-    let len = U256::from(vs.len());
-    len.to_big_endian(&mut buf);
-    ret.extend_from_slice(&buf);
+    fn read_usize(&self, offset: usize) -> Result<usize, SolError> {
+        to_usize(&U256::from_big_endian(self.word(offset)?))
+    }
 
-    // data
+    // `base` is where the tuple starts, offsets of dynamic members are relative to it
+    fn tuple(&mut self, tys: &[SolType], base: usize) -> Result<Vec<SolValue>, SolError> {
+        let mut ret = Vec::with_capacity(tys.len());
+        let mut head = base;
+        for ty in tys {
+            let v = if ty.is_dynamic() {
+                let offset = self.read_usize(head)?;
+                let at = base.checked_add(offset).ok_or(SolError::Overflow)?;
+                self.value(ty, at)?
+            } else {
+                self.value(ty, head)?
+            };
+            ret.push(v);
+            head = head
+                .checked_add(ty.head_size().ok_or(SolError::Overflow)?)
+                .ok_or(SolError::Overflow)?;
+        }
+        Ok(ret)
+    }
+
+    fn value(&mut self, ty: &SolType, at: usize) -> Result<SolValue, SolError> {
+        self.budget = self
+            .budget
+            .checked_sub(1)
+            .ok_or(SolError::InvalidValue("too many values"))?;
+        match ty {
+            SolType::Uint(bits) => {
+                let v = U256::from_big_endian(self.word(at)?);
+                if *bits < 256 && v >> *bits != U256::zero() {
+                    return Err(SolError::InvalidValue("uint out of range"));
+                }
+                Ok(SolValue::Uint(v))
+            }
+            SolType::Int(bits) => {
+                let v = U256::from_big_endian(self.word(at)?);
+                if *bits < 256 {
+                    // upper bits must be the sign extension
+                    let upper = v >> (*bits - 1);
+                    let all = U256::MAX >> (*bits - 1);
+                    if upper != U256::zero() && upper != all {
+                        return Err(SolError::InvalidValue("int out of range"));
+                    }
+                }
+                Ok(SolValue::Int(v))
+            }
+            SolType::Address => {
+                let w = self.word(at)?;
+                if w[..12].iter().any(|v| *v != 0) {
+                    return Err(SolError::InvalidValue("dirty address"));
+                }
+                Ok(SolValue::Address(H160::from_slice(&w[12..])))
+            }
+            SolType::Bool => {
+                let w = self.word(at)?;
+                if w[..31].iter().any(|v| *v != 0) || w[31] > 1 {
+                    return Err(SolError::InvalidValue("dirty bool"));
+                }
+                Ok(SolValue::Bool(w[31] == 1))
+            }
+            SolType::FixedBytes(n) => {
+                let w = self.word(at)?;
+                if w[*n..].iter().any(|v| *v != 0) {
+                    return Err(SolError::InvalidValue("dirty fixed bytes"));
+                }
+                Ok(SolValue::FixedBytes(w[..*n].to_vec()))
+            }
+            SolType::Bytes => {
+                let len = self.read_usize(at)?;
+                let start = at.checked_add(WORD).ok_or(SolError::Overflow)?;
+                Ok(SolValue::Bytes(slice(self.data, start, len)?.to_vec()))
+            }
+            SolType::String => {
+                let len = self.read_usize(at)?;
+                let start = at.checked_add(WORD).ok_or(SolError::Overflow)?;
+                let bytes = slice(self.data, start, len)?.to_vec();
+                String::from_utf8(bytes)
+                    .map(SolValue::String)
+                    .map_err(|_| SolError::InvalidValue("invalid utf8"))
+            }
+            SolType::Array(elem) => {
+                let len = self.read_usize(at)?;
+                if len > self.budget {
+                    return Err(SolError::InvalidValue("too many values"));
+                }
+                let base = at.checked_add(WORD).ok_or(SolError::Overflow)?;
+                // reject lengths the remaining data can't possibly hold before allocating
+                let need = elem
+                    .head_size()
+                    .and_then(|v| v.checked_mul(len))
+                    .ok_or(SolError::Overflow)?;
+                slice(self.data, base, need)?;
+                let tys = (0..len).map(|_| elem.as_ref().clone()).collect::<Vec<_>>();
+                self.tuple(&tys, base).map(SolValue::Array)
+            }
+            SolType::FixedArray(elem, n) => {
+                if *n > self.budget {
+                    return Err(SolError::InvalidValue("too many values"));
+                }
+                let tys = (0..*n).map(|_| elem.as_ref().clone()).collect::<Vec<_>>();
+                self.tuple(&tys, at).map(SolValue::FixedArray)
+            }
+            SolType::Tuple(tys) => self.tuple(tys, at).map(SolValue::Tuple),
+        }
+    }
+}
+
+fn encode_tuple(vs: &[SolValue]) -> Vec<u8> {
+    let heads_len = vs
+        .iter()
+        .map(|v| {
+            if v.is_dynamic() {
+                WORD
+            } else {
+                encode_value(v).len()
+            }
+        })
+        .sum::<usize>();
+    let mut head = Vec::with_capacity(heads_len);
+    let mut tail = vec![];
     for v in vs {
-        v.to_big_endian(&mut buf);
-        ret.extend_from_slice(&buf);
+        let encoded = encode_value(v);
+        if v.is_dynamic() {
+            head.extend_from_slice(&encode_uint256(&U256::from(heads_len + tail.len())));
+            tail.extend_from_slice(&encoded);
+        } else {
+            head.extend_from_slice(&encoded);
+        }
+    }
+    head.extend_from_slice(&tail);
+    head
+}
+
+fn encode_value(v: &SolValue) -> Vec<u8> {
+    match v {
+        SolValue::Uint(v) | SolValue::Int(v) => encode_uint256(v).to_vec(),
+        SolValue::Address(v) => encode_address(v).to_vec(),
+        SolValue::Bool(v) => encode_uint256(&U256::from(*v as u8)).to_vec(),
+        SolValue::FixedBytes(v) => {
+            let mut buf = [0; WORD];
+            let n = v.len().min(WORD);
+            buf[..n].copy_from_slice(&v[..n]);
+            buf.to_vec()
+        }
+        SolValue::Bytes(v) => encode_bytes(v),
+        SolValue::String(v) => encode_bytes(v.as_bytes()),
+        SolValue::Array(vs) => {
+            let mut ret = encode_uint256(&U256::from(vs.len())).to_vec();
+            ret.extend_from_slice(&encode_tuple(vs));
+            ret
+        }
+        SolValue::FixedArray(vs) | SolValue::Tuple(vs) => encode_tuple(vs),
     }
+}
+
+// len + right padded data
+fn encode_bytes(data: &[u8]) -> Vec<u8> {
+    let padded = (data.len() + WORD - 1) / WORD * WORD;
+    let mut ret = Vec::with_capacity(WORD + padded);
+    ret.extend_from_slice(&encode_uint256(&U256::from(data.len())));
+    ret.extend_from_slice(data);
+    ret.resize(WORD + padded, 0);
     ret
 }
+
+pub fn encode_address(addr: &H160) -> [u8; 32] {
+    let mut buf = [0; 32];
+    buf[12..].copy_from_slice(addr.as_bytes());
+    buf
+}
+
+pub fn encode_uint256(v: &U256) -> [u8; 32] {
+    let mut buf = [0; 32];
+    v.to_big_endian(&mut buf);
+    buf
+}
+
+// `data` offseted, a missing word is reported as `None`
+pub fn decode_uint256(data: &[u8]) -> Option<U256> {
+    word(data, 0).ok().map(U256::from_big_endian)
+}
+
+pub fn encode_uint256_array(vs: &Vec<U256>) -> Vec<u8> {
+    let vs = vs.iter().map(|v| SolValue::Uint(*v)).collect();
+    encode(&[SolValue::Array(vs)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    fn words(ws: &[&str]) -> Vec<u8> {
+        hex::decode(ws.concat()).unwrap()
+    }
+
+    fn types(s: &[&str]) -> Vec<SolType> {
+        s.iter().map(|v| SolType::parse(v).unwrap()).collect()
+    }
+
+    fn uint(v: u64) -> SolValue {
+        SolValue::Uint(U256::from(v))
+    }
+
+    fn word_of(v: u64) -> Vec<u8> {
+        encode_uint256(&U256::from(v)).to_vec()
+    }
+
+    fn random_bytes(rng: &mut Rng, n: usize) -> Vec<u8> {
+        (0..n).map(|_| rng.next_u64() as u8).collect()
+    }
+
+    // a random type nested up to `depth` levels. tuples and fixed arrays have
+    // two members at least, so their encodings stay within the decoder budget
+    fn random_type(rng: &mut Rng, depth: usize) -> SolType {
+        let kinds = if depth == 0 { 7 } else { 10 };
+        match rng.below(kinds) {
+            0 => SolType::Uint(8 * (rng.below(32) as usize + 1)),
+            1 => SolType::Int(8 * (rng.below(32) as usize + 1)),
+            2 => SolType::Address,
+            3 => SolType::Bool,
+            4 => SolType::FixedBytes(rng.below(32) as usize + 1),
+            5 => SolType::Bytes,
+            6 => SolType::String,
+            7 => SolType::Array(Box::new(random_type(rng, depth - 1))),
+            8 => {
+                let n = rng.below(2) as usize + 2;
+                SolType::FixedArray(Box::new(random_type(rng, depth - 1)), n)
+            }
+            _ => {
+                let n = rng.below(2) + 2;
+                SolType::Tuple((0..n).map(|_| random_type(rng, depth - 1)).collect())
+            }
+        }
+    }
+
+    // a random value of `ty`, in the form `decode` gives it back
+    fn random_value(rng: &mut Rng, ty: &SolType) -> SolValue {
+        let random_word = |rng: &mut Rng| U256::from_big_endian(&random_bytes(rng, WORD));
+        match ty {
+            SolType::Uint(bits) => SolValue::Uint(random_word(rng) >> (256 - bits)),
+            SolType::Int(bits) => {
+                let v = random_word(rng) >> (256 - bits);
+                // sign extended
+                match *bits < 256 && v >> (bits - 1) == U256::one() {
+                    true => SolValue::Int(v | (U256::MAX << *bits)),
+                    false => SolValue::Int(v),
+                }
+            }
+            SolType::Address => SolValue::Address(H160::from_slice(&random_bytes(rng, 20))),
+            SolType::Bool => SolValue::Bool(rng.below(2) == 1),
+            SolType::FixedBytes(n) => SolValue::FixedBytes(random_bytes(rng, *n)),
+            SolType::Bytes => {
+                let n = rng.below(70) as usize;
+                SolValue::Bytes(random_bytes(rng, n))
+            }
+            SolType::String => {
+                let chars = "aZ0 é€🦀".chars().collect::<Vec<_>>();
+                let n = rng.below(20);
+                SolValue::String(
+                    (0..n)
+                        .map(|_| chars[rng.below(chars.len() as u64) as usize])
+                        .collect(),
+                )
+            }
+            SolType::Array(ty) => {
+                let n = rng.below(4);
+                SolValue::Array((0..n).map(|_| random_value(rng, ty)).collect())
+            }
+            SolType::FixedArray(ty, n) => {
+                SolValue::FixedArray((0..*n).map(|_| random_value(rng, ty)).collect())
+            }
+            SolType::Tuple(tys) => {
+                SolValue::Tuple(tys.iter().map(|v| random_value(rng, v)).collect())
+            }
+        }
+    }
+
+    fn random_types(rng: &mut Rng) -> Vec<SolType> {
+        let n = rng.below(4) + 1;
+        (0..n).map(|_| random_type(rng, 3)).collect()
+    }
+
+    // `sam(bytes,bool,uint256[])` with ("dave", true, [1, 2, 3]) from the abi spec
+    fn sam() -> (Vec<SolType>, Vec<SolValue>, Vec<u8>) {
+        let tys = types(&["bytes", "bool", "uint256[]"]);
+        let vs = vec![
+            SolValue::Bytes(b"dave".to_vec()),
+            SolValue::Bool(true),
+            SolValue::Array(vec![uint(1), uint(2), uint(3)]),
+        ];
+        let data = words(&[
+            "0000000000000000000000000000000000000000000000000000000000000060",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "00000000000000000000000000000000000000000000000000000000000000a0",
+            "0000000000000000000000000000000000000000000000000000000000000004",
+            "6461766500000000000000000000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000003",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "0000000000000000000000000000000000000000000000000000000000000002",
+            "0000000000000000000000000000000000000000000000000000000000000003",
+        ]);
+        (tys, vs, data)
+    }
+
+    #[test]
+    fn parse_types() {
+        assert_eq!(
+            SolType::parse("(address,bool,bytes)[]").unwrap(),
+            SolType::Array(Box::new(SolType::Tuple(vec![
+                SolType::Address,
+                SolType::Bool,
+                SolType::Bytes
+            ])))
+        );
+        assert_eq!(
+            SolType::parse("uint256[2][]").unwrap(),
+            SolType::Array(Box::new(SolType::FixedArray(
+                Box::new(SolType::Uint(256)),
+                2
+            )))
+        );
+        assert_eq!(SolType::parse("int").unwrap(), SolType::Int(256));
+        for v in ["uint7", "uint264", "bytes33", "bytes0", "(address", "foo"] {
+            assert!(SolType::parse(v).is_err(), "{}", v);
+        }
+    }
+
+    #[test]
+    fn known_vectors() {
+        // `baz(uint32,bool)` with (69, true)
+        let tys = types(&["uint32", "bool"]);
+        let vs = vec![uint(69), SolValue::Bool(true)];
+        let data = words(&[
+            "0000000000000000000000000000000000000000000000000000000000000045",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+        ]);
+        assert_eq!(encode(&vs), data);
+        assert_eq!(decode(&tys, &data).unwrap(), vs);
+
+        let (tys, vs, data) = sam();
+        assert_eq!(encode(&vs), data);
+        assert_eq!(decode(&tys, &data).unwrap(), vs);
+
+        // `f(uint256,uint32[],bytes10,bytes)` with
+        // (0x123, [0x456, 0x789], "1234567890", "Hello, world!")
+        let tys = types(&["uint256", "uint32[]", "bytes10", "bytes"]);
+        let vs = vec![
+            uint(0x123),
+            SolValue::Array(vec![uint(0x456), uint(0x789)]),
+            SolValue::FixedBytes(b"1234567890".to_vec()),
+            SolValue::Bytes(b"Hello, world!".to_vec()),
+        ];
+        let data = words(&[
+            "0000000000000000000000000000000000000000000000000000000000000123",
+            "0000000000000000000000000000000000000000000000000000000000000080",
+            "3132333435363738393000000000000000000000000000000000000000000000",
+            "00000000000000000000000000000000000000000000000000000000000000e0",
+            "0000000000000000000000000000000000000000000000000000000000000002",
+            "0000000000000000000000000000000000000000000000000000000000000456",
+            "0000000000000000000000000000000000000000000000000000000000000789",
+            "000000000000000000000000000000000000000000000000000000000000000d",
+            "48656c6c6f2c20776f726c642100000000000000000000000000000000000000",
+        ]);
+        assert_eq!(encode(&vs), data);
+        assert_eq!(decode(&tys, &data).unwrap(), vs);
+    }
+
+    #[test]
+    fn round_trip() {
+        let tys = types(&[
+            "address",
+            "int64",
+            "string",
+            "(address,bool,bytes)[]",
+            "uint256[2][]",
+            "bytes32",
+        ]);
+        let minus_one = SolValue::Int(U256::MAX);
+        let vs = vec![
+            SolValue::Address(H160::from_slice(&[0xaa; 20])),
+            minus_one,
+            SolValue::String("1rpc".into()),
+            SolValue::Array(vec![
+                SolValue::Tuple(vec![
+                    SolValue::Address(H160::from_slice(&[0x01; 20])),
+                    SolValue::Bool(false),
+                    SolValue::Bytes(vec![0xff; 40]),
+                ]),
+                SolValue::Tuple(vec![
+                    SolValue::Address(H160::from_slice(&[0x02; 20])),
+                    SolValue::Bool(true),
+                    SolValue::Bytes(vec![]),
+                ]),
+            ]),
+            SolValue::Array(vec![
+                SolValue::FixedArray(vec![uint(1), uint(2)]),
+                SolValue::FixedArray(vec![uint(3), uint(4)]),
+            ]),
+            SolValue::FixedBytes(vec![0x11; 32]),
+        ];
+        let data = encode(&vs);
+        assert_eq!(data.len() % WORD, 0);
+        assert_eq!(decode(&tys, &data).unwrap(), vs);
+    }
+
+    #[test]
+    fn truncated_input() {
+        let (tys, _, data) = sam();
+        for len in [0, 31, 32 * 3, data.len() - 1] {
+            assert!(
+                matches!(decode(&tys, &data[..len]), Err(SolError::ShortData { .. })),
+                "{}",
+                len
+            );
+        }
+        assert!(split_selector(&[0x12, 0x34, 0x56]).is_err());
+    }
+
+    #[test]
+    fn oversized_lengths() {
+        let tys = types(&["bytes"]);
+        // beyond what an offset can be
+        let mut data = word_of(0x20);
+        data.extend(encode_uint256(&(U256::from(u32::MAX) + 1)));
+        assert_eq!(decode(&tys, &data), Err(SolError::Overflow));
+
+        // more than the data holds
+        let mut data = word_of(0x20);
+        data.extend(word_of(1000));
+        data.extend(vec![0; 64]);
+        assert!(matches!(
+            decode(&tys, &data),
+            Err(SolError::ShortData { .. })
+        ));
+
+        // an array longer than the budget is refused before allocating
+        let tys = types(&["uint256[]"]);
+        let mut data = word_of(0x20);
+        data.extend(word_of(u32::MAX as u64));
+        assert_eq!(
+            decode(&tys, &data),
+            Err(SolError::InvalidValue("too many values"))
+        );
+    }
+
+    #[test]
+    fn shared_offsets_hit_the_budget() {
+        // 8 inner arrays all pointing at the same 8 values, 73 values out of
+        // 19 words, more than a well-formed encoding could hold
+        let tys = types(&["uint256[][]"]);
+        let mut data = word_of(0x20);
+        data.extend(word_of(8));
+        for _ in 0..8 {
+            data.extend(word_of(8 * 32));
+        }
+        data.extend(word_of(8));
+        for v in 0..8 {
+            data.extend(word_of(v));
+        }
+        assert_eq!(
+            decode(&tys, &data),
+            Err(SolError::InvalidValue("too many values"))
+        );
+
+        // the same values laid out once fit
+        let inner = SolValue::Array((0..8).map(uint).collect());
+        let vs = vec![SolValue::Array(vec![inner.clone(), inner])];
+        assert_eq!(decode(&tys, &encode(&vs)).unwrap(), vs);
+    }
+
+    #[test]
+    fn dirty_words() {
+        let mut data = word_of(0);
+        data[0] = 1;
+        assert_eq!(
+            decode(&types(&["address"]), &data),
+            Err(SolError::InvalidValue("dirty address"))
+        );
+        assert_eq!(
+            decode(&types(&["bool"]), &word_of(2)),
+            Err(SolError::InvalidValue("dirty bool"))
+        );
+        assert_eq!(
+            decode(&types(&["uint8"]), &word_of(256)),
+            Err(SolError::InvalidValue("uint out of range"))
+        );
+    }
+
+    #[test]
+    fn random_round_trips() {
+        let mut rng = Rng::new(0x5eed);
+        for _ in 0..500 {
+            let tys = random_types(&mut rng);
+            let vs = tys
+                .iter()
+                .map(|v| random_value(&mut rng, v))
+                .collect::<Vec<_>>();
+            let data = encode(&vs);
+            assert_eq!(data.len() % WORD, 0);
+            assert_eq!(decode(&tys, &data).as_ref(), Ok(&vs), "{:?}", tys);
+        }
+    }
+
+    #[test]
+    fn random_input_never_panics() {
+        let mut rng = Rng::new(0xf0220);
+        for _ in 0..2000 {
+            let tys = random_types(&mut rng);
+
+            // garbage, mostly small words so offsets and lengths land inside
+            let mut data = vec![];
+            for _ in 0..rng.below(12) {
+                match rng.below(3) {
+                    0 => data.extend(random_bytes(&mut rng, WORD)),
+                    _ => data.extend(word_of(rng.below(400))),
+                }
+            }
+            let _ = decode(&tys, &data);
+
+            // a valid encoding, cut short and with a flipped bit
+            let vs = tys
+                .iter()
+                .map(|v| random_value(&mut rng, v))
+                .collect::<Vec<_>>();
+            let mut data = encode(&vs);
+            let len = rng.below(data.len() as u64 + 1) as usize;
+            let _ = decode(&tys, &data[..len]);
+            if !data.is_empty() {
+                let at = rng.below(data.len() as u64) as usize;
+                data[at] ^= 1 << rng.below(8);
+                let _ = decode(&tys, &data);
+            }
+        }
+    }
+}
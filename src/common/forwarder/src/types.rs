@@ -2,7 +2,7 @@ use std::prelude::v1::*;
 
 use std::collections::BTreeMap;
 use std::ops::{Deref, DerefMut};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use jsonrpc::{
     Batchable, JsonrpcErrorObj, JsonrpcErrorResponse, JsonrpcRawResponseFull,
@@ -10,8 +10,19 @@ use jsonrpc::{
 };
 use net_http::{HttpMethod, HttpRequestBuilder, Uri};
 
+use crate::client;
+use crate::mixer::{Clock, Mixer};
+use crate::route::{self, JsonrpcRoute, SplitPolicy};
 use crate::sanitizer::{SanitizedRequest, Transform};
 
+// error code of client requests whose upstream request timed out
+pub const UPSTREAM_TIMEOUT_CODE: i64 = -32010;
+// error code of client requests whose upstream connection broke, and that
+// can't be sent again
+pub const UPSTREAM_LOST_CODE: i64 = -32011;
+// error code of broken down calls whose block couldn't be resolved upstream
+pub const BLOCK_UNRESOLVED_CODE: i64 = -32012;
+
 // req
 pub struct JsonrpcForwardContext<'a> {
     pub token: Option<&'a str>,
@@ -20,44 +31,272 @@ pub struct JsonrpcForwardContext<'a> {
 pub struct JsonrpcForwardRequest {
     pub conn_id: usize,
     pub rpc_path: String,
-    pub remote_uri: Uri,
+    pub route: JsonrpcRoute,
     pub sr: SanitizedRequest,
-    pub last_send: Option<Instant>,
+    pub parts: Vec<UpstreamPart>,
+    pub cover: bool,      // cover traffic, no client waits for it
+    pub failovers: usize, // left to spend on failed parts
+    pub base: usize,      // upstream the parts start from
+    pub ua: String,       // User-Agent of all its upstream requests
+}
+
+// one http request carrying some of `sr.req_body` to one upstream
+pub struct UpstreamPart {
+    pub upstream: usize,     // index of `route.upstreams`
+    pub reqs: Vec<usize>,    // indices of `sr.req_body`
+    pub release_at: Instant, // held by the mixer until then
+    pub sent_at: Option<Instant>,
+    pub send_id: Option<usize>, // of the latest attempt, earlier ones are stale
+    pub response: Option<Result<Batchable<JsonrpcResponseRawResult>, String>>,
 }
 
 impl JsonrpcForwardRequest {
-    pub fn build_http_request(&self) -> HttpRequestBuilder {
-        let SanitizedRequest {
-            original_ids: _,
-            req_body,
-            tr,
-        } = &self.sr;
-        let mut header_override = None;
-        for v in tr {
-            match v {
-                Transform::Metadata {
-                    protected,
-                    unprotected: _,
-                } => {
-                    header_override = Some(("User-Agent".to_owned(), protected.ua.clone()));
-                    break;
+    pub fn new(
+        conn_id: usize,
+        rpc_path: String,
+        route: JsonrpcRoute,
+        sr: SanitizedRequest,
+    ) -> Self {
+        let failovers = route.options.failovers();
+        Self {
+            conn_id,
+            rpc_path,
+            route,
+            sr,
+            failovers,
+            parts: vec![],
+            cover: false,
+            base: conn_id,
+            ua: route::UPSTREAM_UA.into(),
+        }
+    }
+
+    // (re)build parts from `sr.req_body` according to the route's split policy,
+    // each part is held by the mixer for its own delay
+    pub fn split<C: Clock>(&mut self, mixer: &mut Mixer<C>) {
+        let policy = self.route.options.split_policy;
+        let n = self.route.upstreams.len().max(1);
+        // start from the picked upstream, and move on to the next ones when
+        // retrying
+        let seed = self.base.wrapping_add(self.sr.retries());
+        let groups = self.sr.dispatch_groups(policy != SplitPolicy::Batch);
+        let budget = Duration::from_millis(self.route.options.mix_delay_ms);
+        let now = mixer.now();
+        let mut delays = vec![];
+        self.parts = groups
+            .into_iter()
+            .enumerate()
+            .map(|(idx, reqs)| {
+                let delay = mixer.delay(budget);
+                delays.push(delay.as_millis() as u64);
+                let pinned = reqs.first().and_then(|v| self.sr.pinned_upstream(*v));
+                UpstreamPart {
+                    upstream: match (pinned, policy) {
+                        (Some(v), _) => v % n,
+                        (None, SplitPolicy::Spread) => seed.wrapping_add(idx) % n,
+                        (None, _) => seed % n,
+                    },
+                    reqs,
+                    release_at: now + delay,
+                    sent_at: None,
+                    send_id: None,
+                    response: None,
                 }
-                _ => continue,
+            })
+            .collect();
+        if !budget.is_zero() {
+            self.sr.tr.push(Transform::timing(delays));
+        }
+    }
+
+    // client pool key and uri of the part's upstream
+    pub fn upstream(&self, part: usize) -> (String, &Uri) {
+        let idx = self.parts[part].upstream;
+        let key = client::pool_key(&self.rpc_path, idx);
+        (key, &self.route.upstreams[idx])
+    }
+
+    pub fn is_answered(&self) -> bool {
+        self.parts.iter().all(|v| v.response.is_some())
+    }
+
+    // when the part was written upstream, if it's still waiting for the answer
+    pub fn in_flight(&self, part: usize) -> Option<Instant> {
+        let part = self.parts.get(part)?;
+        match (part.send_id, &part.response) {
+            (Some(_), None) => part.sent_at,
+            _ => None,
+        }
+    }
+
+    // parts not written upstream within the connect timeout of their
+    // release, or not answered within the read timeout of their methods
+    pub fn expired(&self, now: Instant) -> Vec<(usize, Duration)> {
+        let timeouts = &self.route.options.timeouts;
+        let mut expired = vec![];
+        for (idx, part) in self.parts.iter().enumerate() {
+            if part.response.is_some() {
+                continue;
             }
+            let (since, timeout) = match part.sent_at {
+                Some(sent_at) => (sent_at, timeouts.read(self.part_methods(idx))),
+                None => (part.release_at, timeouts.connect()),
+            };
+            let elapsed = now.saturating_duration_since(since);
+            if elapsed > timeout {
+                expired.push((idx, elapsed));
+            }
+        }
+        expired
+    }
+
+    // send the unanswered part again, to the next upstream. only if it isn't
+    // pinned to its upstream, and it may be resent
+    pub fn failover(&mut self, part: usize, now: Instant) -> bool {
+        if self.is_pinned(part) || !self.resend(part, now) {
+            return false;
+        }
+        let n = self.route.upstreams.len().max(1);
+        let part = &mut self.parts[part];
+        part.upstream = (part.upstream + 1) % n;
+        true
+    }
+
+    // send the unanswered part again, to the same upstream. only if all of its
+    // methods are idempotent, and the budget isn't spent
+    pub fn resend(&mut self, part: usize, now: Instant) -> bool {
+        if self.failovers == 0
+            || !self
+                .part_methods(part)
+                .into_iter()
+                .all(route::is_idempotent)
+        {
+            return false;
+        }
+        self.failovers -= 1;
+        let part = &mut self.parts[part];
+        part.release_at = now;
+        part.sent_at = None;
+        part.send_id = None;
+        true
+    }
+
+    // send the unsent part to another upstream, unless it's pinned
+    pub fn reroute(&mut self, part: usize, upstream: usize) -> bool {
+        if self.is_pinned(part) {
+            return false;
         }
-        let body = serde_json::to_vec(req_body).unwrap();
-        HttpRequestBuilder::new_ex(self.remote_uri.clone(), Some(body), |req| {
+        self.parts[part].upstream = upstream;
+        true
+    }
+
+    fn is_pinned(&self, part: usize) -> bool {
+        self.parts[part]
+            .reqs
+            .iter()
+            .any(|v| self.sr.pinned_upstream(*v).is_some())
+    }
+
+    fn part_methods(&self, part: usize) -> Vec<&str> {
+        match &self.sr.req_body {
+            Batchable::Single(v) => vec![v.method.as_str()],
+            Batchable::Batch(vs) => self.parts[part]
+                .reqs
+                .iter()
+                .filter_map(|idx| vs.get(*idx))
+                .map(|v| v.method.as_str())
+                .collect(),
+        }
+    }
+
+    // `err` for every request of the client, with its original id
+    pub fn error_response(&self, err: JsonrpcErrorObj) -> Batchable<JsonrpcRawResponseFull> {
+        match &self.sr.original_ids {
+            Batchable::Single(id) => {
+                Batchable::Single(JsonrpcRawResponseFull::err(err, Some(id.clone())))
+            }
+            Batchable::Batch(ids) => Batchable::Batch(
+                ids.iter()
+                    .map(|id| JsonrpcRawResponseFull::err(err.clone(), Some(id.clone())))
+                    .collect(),
+            ),
+        }
+    }
+
+    // responses of all parts, gathered as if `sr.req_body` is sent at once
+    pub fn take_response(&mut self) -> Option<Result<Batchable<JsonrpcResponseRawResult>, String>> {
+        if !self.is_answered() {
+            return None;
+        }
+        if self.parts.len() == 1 {
+            return self.parts[0].response.take();
+        }
+
+        let mut out = vec![];
+        for part in &mut self.parts {
+            let err = match part.response.take() {
+                Some(Ok(Batchable::Batch(rs))) => {
+                    out.extend(rs);
+                    continue;
+                }
+                Some(Ok(Batchable::Single(JsonrpcResponseRawResult::Err(e)))) if e.id.is_none() => {
+                    e.error
+                }
+                Some(Ok(Batchable::Single(r))) => {
+                    out.push(r);
+                    continue;
+                }
+                Some(Err(e)) => {
+                    // nothing of the upstream response is passed on
+                    glog::error!("[{}] parse upstream response fail: {}", self.rpc_path, e);
+                    JsonrpcErrorObj::error(-32700, "Parse error".into())
+                }
+                None => JsonrpcErrorObj::unknown("unknown error"),
+            };
+            // the whole part is rejected, fail every request in it
+            if let Batchable::Batch(vs) = &self.sr.req_body {
+                for v in part.reqs.iter().filter_map(|idx| vs.get(*idx)) {
+                    out.push(JsonrpcRawResponseFull::err(err.clone(), Some(v.id.clone())).into());
+                }
+            }
+        }
+        Some(Ok(Batchable::Batch(out)))
+    }
+
+    fn build_part_body(&self, part: usize) -> Vec<u8> {
+        let reqs = &self.parts[part].reqs;
+        match &self.sr.req_body {
+            Batchable::Batch(vs) if reqs.len() != vs.len() => {
+                let mut vs = reqs
+                    .iter()
+                    .filter_map(|idx| self.sr.upstream_request(*idx))
+                    .collect::<Vec<_>>();
+                if vs.len() == 1 {
+                    serde_json::to_vec(&vs.remove(0)).unwrap()
+                } else {
+                    serde_json::to_vec(&vs).unwrap()
+                }
+            }
+            _ => self.sr.upstream_body(),
+        }
+    }
+
+    pub fn build_http_request(&self, part: usize) -> HttpRequestBuilder {
+        let body = self.build_part_body(part);
+        let (_, uri) = self.upstream(part);
+        HttpRequestBuilder::new_ex(uri.clone(), Some(body), |req| {
             req.method(HttpMethod::Post);
             req.header("Content-Type", "application/json");
             req.header("Connection", "keep-alive");
-            if let Some((k, v)) = header_override {
+            req.header("User-Agent", &self.ua);
+            for (k, v) in self.route.options.headers.fixed() {
                 req.header(&k, &v);
             }
         })
     }
 
     pub fn build_ws_request(&self) -> Vec<u8> {
-        serde_json::to_vec(&self.sr.req_body).unwrap()
+        self.sr.upstream_body()
     }
 }
 
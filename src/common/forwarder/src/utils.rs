@@ -1,16 +1,15 @@
-use std::{
-    net::{SocketAddr, ToSocketAddrs},
-    prelude::v1::*,
-};
+use std::{net::SocketAddr, prelude::v1::*};
 
+use eth_types::H160;
 use hex::HexBytes;
-use jsonrpc::JsonrpcRawRequest;
+use jsonrpc::{JsonrpcErrorObj, JsonrpcRawRequest, JsonrpcResponseRawResult};
 use net_http::HttpResponseBuilder;
 use serde::Deserialize;
 
+use crate::proxy::TrustedProxies;
+
 #[derive(Deserialize)]
 struct EthCallParamTxn {
-    #[allow(unused)]
     to: String,
     // omit fields
     // from: Option<String>,
@@ -32,10 +31,53 @@ pub fn get_eth_call_data_from_jsonrpc(req: &JsonrpcRawRequest) -> Option<HexByte
         .and_then(|v| v.data)
 }
 
-fn get_header_from_http_req(
-    req: &mut net_http::HttpRequestReader,
-    hdr: &'static str,
-) -> Option<String> {
+pub fn get_eth_call_to_from_jsonrpc(req: &JsonrpcRawRequest) -> Option<H160> {
+    get_jsonrpc_param(req, 0)
+        .and_then(|v| serde_json::from_value::<EthCallParamTxn>(v).ok())
+        .and_then(|v| parse_address(&v.to))
+}
+
+// "0x" prefixed, 20 bytes
+pub fn parse_address(s: &str) -> Option<H160> {
+    let data = hex::decode(s.strip_prefix("0x")?).ok()?;
+    if data.len() != 20 {
+        return None;
+    }
+    Some(H160::from_slice(&data))
+}
+
+pub fn get_jsonrpc_param(req: &JsonrpcRawRequest, idx: usize) -> Option<serde_json::Value> {
+    serde_json::from_raw_value::<Vec<serde_json::Value>>(&req.params)
+        .ok()
+        .and_then(|v| v.into_iter().nth(idx))
+}
+
+pub fn get_response_id(resp: &JsonrpcResponseRawResult) -> Option<&jsonrpc::Id> {
+    match resp {
+        JsonrpcResponseRawResult::Ok(v) => Some(&v.id),
+        JsonrpcResponseRawResult::Err(v) => v.id.as_ref(),
+    }
+}
+
+// revert data carried by an `eth_call` error, `None` if it's not a revert
+pub fn get_revert_data(err: &JsonrpcErrorObj) -> Option<Vec<u8>> {
+    let err = serde_json::to_value(err).ok()?;
+    let code = err.get("code").and_then(|v| v.as_i64());
+    let msg = err
+        .get("message")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+    if code != Some(3) && !msg.contains("revert") {
+        return None;
+    }
+    let data = err
+        .get("data")
+        .and_then(|v| v.as_str())
+        .and_then(|v| hex::decode(v.trim_start_matches("0x")).ok());
+    Some(data.unwrap_or_default())
+}
+
+fn get_header_from_http_req(req: &mut net_http::HttpRequestReader, hdr: &str) -> Option<String> {
     req.headers(|headers| {
         for header in headers {
             if header.name.eq_ignore_ascii_case(hdr) {
@@ -46,47 +88,57 @@ fn get_header_from_http_req(
     })
 }
 
-pub fn get_host_ip(req: &mut net_http::HttpRequestReader) -> String {
-    let na = String::from("N/A");
-
-    let mut host = match get_header_from_http_req(req, "host") {
-        Some(v) => v,
-        _ => return na,
-    };
-
-    if host.contains("127.0.0.1") || host.contains("localhost") {
-        return "127.0.0.1".into();
-    }
+// every value of a header repeated in the request
+fn get_header_values_from_http_req(
+    req: &mut net_http::HttpRequestReader,
+    hdr: &str,
+) -> Vec<String> {
+    req.headers(|headers| {
+        headers
+            .iter()
+            .filter(|v| v.name.eq_ignore_ascii_case(hdr))
+            .map(|v| String::from_utf8_lossy(v.value).to_string())
+            .collect()
+    })
+}
 
-    if !host.ends_with(":443") {
-        host.push_str(":443");
-    }
-    glog::debug!("query host: {}", host);
-    match host.to_socket_addrs() {
-        Ok(v) => match v.into_iter().next() {
-            Some(v) => v.ip().to_string(),
-            _ => na,
-        },
-        Err(_) => na,
+// headers are only taken from `proxies`, any other peer is the client
+pub fn get_client_ip(
+    req: &mut net_http::HttpRequestReader,
+    peer: Option<SocketAddr>,
+    proxies: &TrustedProxies,
+) -> String {
+    match proxies.client_ip(peer, |hdr| get_header_values_from_http_req(req, hdr)) {
+        Some(v) => v.to_string(),
+        None => "N/A".into(),
     }
 }
 
-pub fn get_client_ip(req: &mut net_http::HttpRequestReader, default: Option<SocketAddr>) -> String {
-    let na = String::from("N/A");
-
-    match get_header_from_http_req(req, "cf-connecting-ip")
-        .or_else(|| get_header_from_http_req(req, "x-forwarded-for"))
-        .or_else(|| default.map(|v| v.ip().to_string()))
-    {
-        Some(v) => v,
-        _ => na,
-    }
+pub fn get_header_names(req: &mut net_http::HttpRequestReader) -> Vec<String> {
+    req.headers(|headers| headers.iter().map(|v| v.name.to_string()).collect())
 }
 
 pub fn get_cilent_ua(req: &mut net_http::HttpRequestReader) -> String {
     get_header_from_http_req(req, "user-agent").unwrap_or_default()
 }
 
+// overloaded or failing upstreams, worth trying another one
+pub fn is_retryable_http_response(resp: &net_http::HttpResponse) -> bool {
+    let code = resp.status.code();
+    code == 429 || (500..600).contains(&code)
+}
+
+// per request of the raw body, whether it has no "id" at all (a
+// notification), which is lost once parsed as `"id": null`
+pub fn absent_ids(data: &[u8]) -> Vec<bool> {
+    let absent = |v: &serde_json::Value| v.get("id").is_none();
+    match serde_json::from_slice::<serde_json::Value>(data) {
+        Ok(serde_json::Value::Array(list)) => list.iter().map(absent).collect(),
+        Ok(v) => vec![absent(&v)],
+        Err(_) => vec![],
+    }
+}
+
 pub fn create_http_jsonrpc_plain_response(body: Vec<u8>) -> Vec<u8> {
     let mut builder = HttpResponseBuilder::new(200).close().json(body);
     builder.to_vec()